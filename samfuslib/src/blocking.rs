@@ -0,0 +1,153 @@
+//! Blocking wrapper around [`crate::fus::FusClient`] for callers that do not
+//! use an async runtime.
+//!
+//! Each client owns a single-threaded tokio runtime that is only driven while
+//! a method is running or a download is being read. Like other blocking
+//! wrappers of async code, the methods panic if they are called from within an
+//! async runtime.
+
+use crate::{
+    fus::{self, DeviceId, DownloadSession, FirmwareInfo, FotaVersion, FusError},
+    retry::RetryPolicy,
+    transport::BodyStream,
+    version::FwVersion,
+};
+
+use std::{
+    io::{self, Read},
+    ops::Range,
+    sync::Arc,
+};
+
+use bytes::{Buf, Bytes};
+use tokio::runtime::{Builder, Runtime};
+use tokio_stream::StreamExt;
+
+/// Blocking version of [`crate::fus::FusClient`]. Use
+/// [`crate::fus::FusClientBuilder::build_blocking`] to create one. All methods
+/// take `&self`, so a single instance can be shared by multiple threads.
+pub struct FusClient {
+    inner: fus::FusClient,
+    runtime: Arc<Runtime>,
+}
+
+impl FusClient {
+    pub(crate) fn new(inner: fus::FusClient) -> Result<Self, FusError> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(FusError::RuntimeError)?;
+
+        Ok(Self {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Get the policy for retrying failed downloads.
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        self.inner.retry_policy()
+    }
+
+    /// See [`crate::fus::FusClient::get_latest_version`].
+    pub fn get_latest_version(&self, model: &str, region: &str) -> Result<FwVersion, FusError> {
+        self.runtime.block_on(self.inner.get_latest_version(model, region))
+    }
+
+    /// See [`crate::fus::FusClient::get_version_history`].
+    pub fn get_version_history(
+        &self,
+        model: &str,
+        region: &str,
+    ) -> Result<Vec<FotaVersion>, FusError> {
+        self.runtime.block_on(self.inner.get_version_history(model, region))
+    }
+
+    /// See [`crate::fus::FusClient::get_latest_version_fus`].
+    pub fn get_latest_version_fus(
+        &self,
+        model: &str,
+        region: &str,
+        factory: bool,
+        device_id: Option<&DeviceId>,
+    ) -> Result<FwVersion, FusError> {
+        self.runtime.block_on(
+            self.inner.get_latest_version_fus(model, region, factory, device_id))
+    }
+
+    /// See [`crate::fus::FusClient::get_firmware_info`].
+    pub fn get_firmware_info(
+        &self,
+        model: &str,
+        region: &str,
+        version: &FwVersion,
+        factory: bool,
+        device_id: Option<&DeviceId>,
+    ) -> Result<FirmwareInfo, FusError> {
+        self.runtime.block_on(
+            self.inner.get_firmware_info(model, region, version, factory, device_id))
+    }
+
+    /// See [`crate::fus::FusClient::start_download`].
+    pub fn start_download(&self, info: &FirmwareInfo) -> Result<DownloadSession, FusError> {
+        self.runtime.block_on(self.inner.start_download(info))
+    }
+
+    /// Create a reader for downloading the specified byte range of the
+    /// firmware that the session was started for. See
+    /// [`crate::fus::FusClient::download_range`].
+    pub fn download_range(
+        &self,
+        session: &DownloadSession,
+        range: Range<u64>,
+    ) -> Result<impl Read + Send, FusError> {
+        let stream = self.runtime.block_on(self.inner.download_range_stream(session, range))?;
+        Ok(self.reader(stream))
+    }
+
+    /// Create a reader for downloading the specified firmware with the
+    /// specified byte range. See [`crate::fus::FusClient::download`].
+    pub fn download(
+        &self,
+        info: &FirmwareInfo,
+        range: Range<u64>,
+    ) -> Result<impl Read + Send, FusError> {
+        let stream = self.runtime.block_on(self.inner.download_stream(info, range))?;
+        Ok(self.reader(stream))
+    }
+
+    fn reader(&self, stream: BodyStream) -> DownloadReader {
+        DownloadReader {
+            runtime: self.runtime.clone(),
+            stream,
+            buf: Bytes::new(),
+        }
+    }
+}
+
+/// Reader that drives the client's runtime to receive download data. Errors
+/// from the stream are reported as [`io::ErrorKind::Other`] with the
+/// [`FusError`] as the inner error.
+struct DownloadReader {
+    runtime: Arc<Runtime>,
+    stream: BodyStream,
+    buf: Bytes,
+}
+
+impl Read for DownloadReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.buf.is_empty() {
+            match self.runtime.block_on(self.stream.next()) {
+                Some(Ok(data)) => self.buf = data,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.buf.len());
+        out[..n].copy_from_slice(&self.buf[..n]);
+        self.buf.advance(n);
+
+        Ok(n)
+    }
+}
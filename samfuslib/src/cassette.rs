@@ -0,0 +1,536 @@
+//! Recording and replaying of HTTP exchanges.
+//!
+//! A [`RecordingTransport`] wraps another transport and stores every request
+//! and response in a [`Cassette`], which can be saved as JSON. A
+//! [`ReplayTransport`] answers requests from a cassette without any network
+//! access. This makes it possible to reproduce a session offline, like for a
+//! bug report.
+//!
+//! By default, header values that contain key material or session state
+//! (Authorization, NONCE, and cookies), the nonce-derived `<LOGIC_CHECK>` and
+//! device identifying `<DEVICE_IMEI_PUSH>` request fields, and the
+//! `<LOGIC_VALUE_*>` response fields, which the firmware encryption key is
+//! derived from, are redacted when recording. Since FUS responses must contain
+//! a nonce, the replay transport substitutes a placeholder nonce encrypted
+//! with the replaying client's keys.
+
+use crate::{
+    crypto::FusKeys,
+    fus::FusError,
+    protocol::{FusRequest, Nonce},
+    transport::{BodyStream, BoxFuture, HttpTransport},
+};
+
+use std::{
+    io::{Read, Write},
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use base64::{
+    Engine,
+    engine::general_purpose::STANDARD,
+};
+use bytes::Bytes;
+use futures_core::Stream;
+use http::{
+    header::{HeaderName, RANGE},
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+/// Placeholder for redacted header values.
+pub const REDACTED: &str = "[redacted]";
+/// Headers whose values are redacted when recording.
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "set-cookie", "nonce"];
+/// XML fields whose values are redacted from request bodies when recording.
+const REDACTED_REQUEST_FIELDS: &[&str] = &["LOGIC_CHECK", "DEVICE_IMEI_PUSH"];
+/// XML fields whose values are redacted from response bodies when recording.
+const REDACTED_RESPONSE_FIELDS: &[&str] = &["LOGIC_VALUE_HOME", "LOGIC_VALUE_FACTORY"];
+/// Nonce sent in place of redacted NONCE headers during replay. Must be 16
+/// bytes.
+const REPLAY_NONCE: &[u8] = b"samfuslib_replay";
+
+/// HTTP message body. Bodies that are valid UTF-8 are stored as text so that
+/// XML documents remain readable.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordedBody {
+    Text(String),
+    Base64(String),
+}
+
+impl RecordedBody {
+    fn new(data: &[u8]) -> Self {
+        match std::str::from_utf8(data) {
+            Ok(s) => Self::Text(s.to_owned()),
+            Err(_) => Self::Base64(STANDARD.encode(data)),
+        }
+    }
+
+    /// Get the raw body data.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, FusError> {
+        match self {
+            Self::Text(s) => Ok(s.as_bytes().to_vec()),
+            Self::Base64(s) => Ok(STANDARD.decode(s)?),
+        }
+    }
+}
+
+/// A recorded request.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+    /// Size of the body before it was truncated. This is only set if the body
+    /// was truncated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_size: Option<u64>,
+}
+
+/// A recorded response. If the body stream failed or was dropped early, the
+/// body only contains the data received up to that point.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+    /// Size of the body before it was truncated. This is only set if the body
+    /// was truncated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_size: Option<u64>,
+}
+
+/// When an exchange took place. This is informational only and is not used
+/// for replaying.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Timings {
+    /// When the request was sent.
+    #[serde(with = "time::serde::rfc3339")]
+    pub started: OffsetDateTime,
+    /// Milliseconds until the response headers were received.
+    pub wait_ms: f64,
+    /// Milliseconds spent receiving the response body.
+    pub receive_ms: f64,
+}
+
+/// A request and the response that it received.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+}
+
+/// A sequence of recorded HTTP exchanges, in the order that the responses
+/// completed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    /// Load a cassette from JSON data.
+    pub fn load(reader: impl Read) -> serde_json::Result<Self> {
+        serde_json::from_reader(reader)
+    }
+
+    /// Save the cassette as JSON data.
+    pub fn save(&self, writer: impl Write) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+}
+
+/// Options for what a [`RecordingTransport`] stores.
+#[derive(Clone, Copy, Debug)]
+struct RecordOptions {
+    redact: bool,
+    max_body_size: Option<usize>,
+}
+
+/// Convert headers to `(name, value)` pairs, optionally redacting sensitive
+/// values.
+fn record_headers(headers: &HeaderMap, redact: bool) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|(name, value)| {
+            let value = if redact && REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Replace the value of every `<field><Data>...</Data></field>` element in an
+/// XML document with [`REDACTED`]. If the closing tag is missing, like in a
+/// truncated body, everything after the opening tag is redacted. Data that is
+/// not valid UTF-8 is returned unmodified.
+fn redact_xml_fields(data: &[u8], fields: &[&str]) -> Vec<u8> {
+    let mut text = match std::str::from_utf8(data) {
+        Ok(s) => s.to_owned(),
+        Err(_) => return data.to_vec(),
+    };
+
+    for field in fields {
+        let start_tag = format!("<{field}><Data>");
+        let mut offset = 0;
+
+        while let Some(start) = text[offset..].find(&start_tag) {
+            let value_start = offset + start + start_tag.len();
+            let value_end = match text[value_start..].find("</Data>") {
+                Some(n) => value_start + n,
+                None => text.len(),
+            };
+
+            text.replace_range(value_start..value_end, REDACTED);
+            offset = value_start + REDACTED.len();
+        }
+    }
+
+    text.into_bytes()
+}
+
+/// Truncate data to the maximum body size. Returns the data and, if it was
+/// truncated, the original size.
+fn truncate_body(data: &[u8], max_size: Option<usize>) -> (&[u8], Option<u64>) {
+    match max_size {
+        Some(n) if data.len() > n => (&data[..n], Some(data.len() as u64)),
+        _ => (data, None),
+    }
+}
+
+fn record_request(request: &FusRequest, options: RecordOptions) -> RecordedRequest {
+    let body = if options.redact {
+        redact_xml_fields(request.body(), REDACTED_REQUEST_FIELDS)
+    } else {
+        request.body().clone()
+    };
+    let (body, original_size) = truncate_body(&body, options.max_body_size);
+
+    RecordedRequest {
+        method: request.method().to_string(),
+        url: request.uri().to_string(),
+        headers: record_headers(request.headers(), options.redact),
+        body: RecordedBody::new(body),
+        original_size,
+    }
+}
+
+/// Body stream that passes data through and stores the interaction in the
+/// cassette when it is dropped.
+struct RecordingStream {
+    inner: BodyStream,
+    request: RecordedRequest,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    body_size: u64,
+    options: RecordOptions,
+    started: OffsetDateTime,
+    wait_ms: f64,
+    received_at: Instant,
+    cassette: Arc<Mutex<Cassette>>,
+}
+
+impl Stream for RecordingStream {
+    type Item = Result<Bytes, FusError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let result = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(data))) = &result {
+            let remaining = match self.options.max_body_size {
+                Some(n) => n.saturating_sub(self.body.len()),
+                None => data.len(),
+            };
+
+            self.body.extend_from_slice(&data[..remaining.min(data.len())]);
+            self.body_size += data.len() as u64;
+        }
+
+        result
+    }
+}
+
+impl Drop for RecordingStream {
+    fn drop(&mut self) {
+        let original_size = Some(self.body_size)
+            .filter(|&n| n != self.body.len() as u64);
+        let body = if self.options.redact {
+            redact_xml_fields(&self.body, REDACTED_RESPONSE_FIELDS)
+        } else {
+            mem::take(&mut self.body)
+        };
+
+        let interaction = Interaction {
+            request: self.request.clone(),
+            response: RecordedResponse {
+                status: self.status,
+                headers: mem::take(&mut self.headers),
+                body: RecordedBody::new(&body),
+                original_size,
+            },
+            timings: Some(Timings {
+                started: self.started,
+                wait_ms: self.wait_ms,
+                receive_ms: self.received_at.elapsed().as_secs_f64() * 1000.0,
+            }),
+        };
+
+        self.cassette.lock().unwrap().interactions.push(interaction);
+    }
+}
+
+/// Transport that records every exchange made through another transport.
+/// Clones share the same cassette, so a clone can be kept for retrieving the
+/// cassette after passing the transport to
+/// [`crate::fus::FusClientBuilder::transport`].
+pub struct RecordingTransport<T> {
+    inner: Arc<T>,
+    cassette: Arc<Mutex<Cassette>>,
+    options: RecordOptions,
+}
+
+impl<T> Clone for RecordingTransport<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cassette: self.cassette.clone(),
+            options: self.options,
+        }
+    }
+}
+
+impl<T: HttpTransport> RecordingTransport<T> {
+    /// Create a transport that sends requests with `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            cassette: Arc::new(Mutex::new(Cassette::default())),
+            options: RecordOptions {
+                redact: true,
+                max_body_size: None,
+            },
+        }
+    }
+
+    /// Set whether key material, nonces, and cookies are redacted. The default
+    /// is true. Disabling this makes the cassette contain secrets.
+    pub fn redact(mut self, enabled: bool) -> Self {
+        self.options.redact = enabled;
+        self
+    }
+
+    /// Set the maximum number of bytes stored for each request and response
+    /// body. The default is unlimited. Cassettes with truncated responses
+    /// cannot be replayed.
+    pub fn max_body_size(mut self, size: Option<usize>) -> Self {
+        self.options.max_body_size = size;
+        self
+    }
+
+    /// Get a copy of the exchanges recorded so far. Exchanges with a response
+    /// body that is still being received are not included.
+    pub fn cassette(&self) -> Cassette {
+        self.cassette.lock().unwrap().clone()
+    }
+}
+
+impl<T: HttpTransport> HttpTransport for RecordingTransport<T> {
+    fn send_streaming(&self, request: FusRequest)
+            -> BoxFuture<'_, Result<Response<BodyStream>, FusError>> {
+        Box::pin(async move {
+            let recorded = record_request(&request, self.options);
+            let started = OffsetDateTime::now_utc();
+            let sent_at = Instant::now();
+            let response = self.inner.send_streaming(request).await?;
+            let status = response.status().as_u16();
+            let headers = record_headers(response.headers(), self.options.redact);
+            let cassette = self.cassette.clone();
+
+            Ok(response.map(|inner| -> BodyStream {
+                Box::pin(RecordingStream {
+                    inner,
+                    request: recorded,
+                    status,
+                    headers,
+                    body: vec![],
+                    body_size: 0,
+                    options: self.options,
+                    started,
+                    wait_ms: sent_at.elapsed().as_secs_f64() * 1000.0,
+                    received_at: Instant::now(),
+                    cassette,
+                })
+            }))
+        })
+    }
+}
+
+/// Transport that answers requests from a cassette.
+///
+/// A request is answered by the first unused interaction with the same method,
+/// URL, and Range header, so concurrent downloads can be replayed even if they
+/// complete in a different order. Other headers and the request body are not
+/// compared because they depend on the nonce. Requests without a matching
+/// interaction fail with [`FusError::TransportError`].
+pub struct ReplayTransport {
+    interactions: Mutex<Vec<Option<Interaction>>>,
+    nonce: String,
+}
+
+impl ReplayTransport {
+    /// Create a transport that replays the cassette. `keys` are used for
+    /// encrypting the placeholder nonce.
+    pub fn new(cassette: Cassette, keys: &FusKeys) -> Self {
+        Self {
+            interactions: Mutex::new(cassette.interactions.into_iter().map(Some).collect()),
+            // Cannot fail because the nonce is 16 bytes
+            nonce: Nonce::from_slice(REPLAY_NONCE).unwrap().to_encrypted(keys),
+        }
+    }
+
+    fn find_interaction(&self, request: &FusRequest) -> Option<Interaction> {
+        let method = request.method().as_str();
+        let url = request.uri().to_string();
+        let range = request.headers().get(RANGE)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+
+        let mut interactions = self.interactions.lock().unwrap();
+        let slot = interactions.iter_mut().find(|i| match i {
+            Some(i) => {
+                let r = &i.request;
+                let recorded_range = r.headers.iter()
+                    .find(|(n, _)| n.as_str() == RANGE.as_str())
+                    .map(|(_, v)| v.clone());
+
+                r.method == method && r.url == url && recorded_range == range
+            }
+            None => false,
+        })?;
+
+        slot.take()
+    }
+
+    fn to_response(&self, recorded: &RecordedResponse) -> Result<Response<Bytes>, FusError> {
+        if recorded.original_size.is_some() {
+            return Err(FusError::TransportError("Recorded response body is truncated".into()));
+        }
+
+        let mut response = Response::new(Bytes::from(recorded.body.to_bytes()?));
+        *response.status_mut() = StatusCode::from_u16(recorded.status)
+            .map_err(|e| FusError::TransportError(e.into()))?;
+
+        for (name, value) in &recorded.headers {
+            let value = if name.eq_ignore_ascii_case("nonce") && value == REDACTED {
+                &self.nonce
+            } else {
+                value
+            };
+
+            response.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| FusError::TransportError(e.into()))?,
+                HeaderValue::from_str(value)
+                    .map_err(|e| FusError::TransportError(e.into()))?,
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    fn send_streaming(&self, request: FusRequest)
+            -> BoxFuture<'_, Result<Response<BodyStream>, FusError>> {
+        Box::pin(async move {
+            let response = self.send(request).await?;
+
+            Ok(response.map(|body| -> BodyStream {
+                Box::pin(tokio_stream::once(Ok(body)))
+            }))
+        })
+    }
+
+    fn send(&self, request: FusRequest) -> BoxFuture<'_, Result<Response<Bytes>, FusError>> {
+        Box::pin(async move {
+            let interaction = self.find_interaction(&request).ok_or_else(|| {
+                FusError::TransportError(format!(
+                    "No recorded response for {} {}", request.method(), request.uri()).into())
+            })?;
+
+            self.to_response(&interaction.response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+
+    use super::*;
+
+    #[test]
+    fn test_record_request() {
+        let request = Request::post("http://localhost/test")
+            .header("Authorization", "FUS signature=\"secret\"")
+            .header("Content-Type", "text/xml")
+            .body(b"<FUSMsg />".to_vec())
+            .unwrap();
+
+        let options = RecordOptions {
+            redact: true,
+            max_body_size: None,
+        };
+
+        let recorded = record_request(&request, options);
+        assert_eq!(recorded.method, "POST");
+        assert_eq!(recorded.url, "http://localhost/test");
+        assert_eq!(recorded.headers, [
+            ("authorization".to_owned(), REDACTED.to_owned()),
+            ("content-type".to_owned(), "text/xml".to_owned()),
+        ]);
+        assert_eq!(recorded.body, RecordedBody::Text("<FUSMsg />".to_owned()));
+        assert_eq!(recorded.original_size, None);
+
+        let recorded = record_request(&request, RecordOptions {
+            redact: false,
+            max_body_size: Some(4),
+        });
+        assert_eq!(recorded.headers[0].1, "FUS signature=\"secret\"");
+        assert_eq!(recorded.body, RecordedBody::Text("<FUS".to_owned()));
+        assert_eq!(recorded.original_size, Some(10));
+
+        let body = RecordedBody::new(b"\xff\x00");
+        assert_eq!(body, RecordedBody::Base64("/wA=".to_owned()));
+        assert_eq!(body.to_bytes().unwrap(), b"\xff\x00");
+    }
+
+    #[test]
+    fn test_redact_xml_fields() {
+        let data = b"<Put><LOGIC_CHECK><Data>abc</Data></LOGIC_CHECK>\
+            <OTHER><Data>def</Data></OTHER><LOGIC_CHECK><Data></Data></LOGIC_CHECK></Put>";
+
+        assert_eq!(
+            std::str::from_utf8(&redact_xml_fields(data, REDACTED_REQUEST_FIELDS)).unwrap(),
+            "<Put><LOGIC_CHECK><Data>[redacted]</Data></LOGIC_CHECK>\
+            <OTHER><Data>def</Data></OTHER><LOGIC_CHECK><Data>[redacted]</Data></LOGIC_CHECK></Put>",
+        );
+        assert_eq!(redact_xml_fields(b"\xff", REDACTED_REQUEST_FIELDS), b"\xff");
+
+        // Truncated value
+        assert_eq!(
+            redact_xml_fields(b"<LOGIC_VALUE_HOME><Data>0123", REDACTED_RESPONSE_FIELDS),
+            b"<LOGIC_VALUE_HOME><Data>[redacted]",
+        );
+    }
+}
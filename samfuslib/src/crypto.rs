@@ -68,6 +68,8 @@ impl FusKeys {
 }
 
 /// Pad byte array to specified block size and optionally truncate to one block.
+// `usize::is_multiple_of` requires Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
 fn pad<B: ArrayLength<u8>>(mut data: &[u8], truncate_to_block_size: bool) -> Vec<u8> {
     let block_size = B::USIZE;
 
@@ -160,6 +162,10 @@ impl FusAes256 {
 pub struct FusFileAes128(Aes128);
 
 impl FusFileAes128 {
+    /// Size of each independently encrypted block. Ciphertext sizes are always
+    /// a multiple of this.
+    pub const BLOCK_SIZE: usize = 16;
+
     /// Create a new cipher instance for decrypting FUS files.
     pub fn new(key: &[u8]) -> Self {
         let ga_key = GenericArray::from_slice(key);
@@ -174,6 +180,16 @@ impl FusFileAes128 {
 
         Ok(())
     }
+
+    /// Encrypt the provided plaintext in-place. This is not needed for talking
+    /// to FUS, but is useful for producing test data.
+    pub fn encrypt_in_place(self, buf: &mut [u8]) -> Result<(), CryptoError> {
+        let buf_size = buf.len();
+        self.0.encrypt_padded_mut::<NoPadding>(buf, buf_size)
+            .map_err(|_| CryptoError::CiphertextTooSmall)?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -266,4 +282,22 @@ mod tests {
                             .decrypt(&hex!("ea016b97268c45b6201797452df6c688a70500f3e18d557474c10a55758b07d9")),
                         Ok(x) if x == hex!("74657374696e675f74657374696e675f"));
     }
+
+    #[test]
+    fn test_file_encrypt_decrypt() {
+        let cipher = FusFileAes128::new(b"testing_testing_");
+
+        let mut buf = *b"testing_testing_testing_testing_";
+        cipher.clone().encrypt_in_place(&mut buf).unwrap();
+        assert_eq!(buf, hex!("65c365f0a7450866fed928b6f93dc58665c365f0a7450866fed928b6f93dc586"));
+
+        cipher.clone().decrypt_in_place(&mut buf).unwrap();
+        assert_eq!(&buf, b"testing_testing_testing_testing_");
+
+        // Data must be a multiple of the block size
+        assert_matches!(cipher.clone().encrypt_in_place(&mut [0u8; 15]),
+                        Err(CryptoError::CiphertextTooSmall));
+        assert_matches!(cipher.decrypt_in_place(&mut [0u8; 17]),
+                        Err(CryptoError::CiphertextTooSmall));
+    }
 }
@@ -0,0 +1,107 @@
+use thiserror::Error;
+
+/// Number of digits in an IMEI, including the check digit
+pub const IMEI_LEN: usize = 15;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum ImeiError {
+    #[error("IMEI prefix must be 1 to {} digits: {0:?}", IMEI_LEN - 1)]
+    InvalidPrefix(String),
+    #[error("IMEI must be {IMEI_LEN} digits: {0:?}")]
+    InvalidLength(String),
+    #[error("IMEI has an invalid check digit: {0:?}")]
+    InvalidCheckDigit(String),
+}
+
+/// Compute the Luhn check digit for a string of ASCII digits. Every second
+/// digit, starting from the rightmost one, is doubled.
+fn luhn_check_digit(digits: &[u8]) -> u8 {
+    let sum: u32 = digits.iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| {
+            let d = u32::from(d - b'0');
+            if i % 2 == 0 {
+                let doubled = d * 2;
+                doubled / 10 + doubled % 10
+            } else {
+                d
+            }
+        })
+        .sum();
+
+    ((10 - sum % 10) % 10) as u8
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Build an IMEI from a prefix, usually the 8-digit Type Allocation Code (TAC)
+/// of the device model. The digits after the prefix are random and the last
+/// digit is a valid Luhn check digit.
+pub fn imei_from_prefix(prefix: &str) -> Result<String, ImeiError> {
+    if !is_digits(prefix) || prefix.len() >= IMEI_LEN {
+        return Err(ImeiError::InvalidPrefix(prefix.to_owned()));
+    }
+
+    let mut digits = prefix.as_bytes().to_vec();
+    while digits.len() < IMEI_LEN - 1 {
+        digits.push(b'0' + fastrand::u8(0..10));
+    }
+    digits.push(b'0' + luhn_check_digit(&digits));
+
+    // Cannot fail because all bytes are ASCII digits
+    Ok(String::from_utf8(digits).unwrap())
+}
+
+/// Check that an IMEI has the right length and a valid Luhn check digit.
+pub fn validate_imei(imei: &str) -> Result<(), ImeiError> {
+    if !is_digits(imei) || imei.len() != IMEI_LEN {
+        return Err(ImeiError::InvalidLength(imei.to_owned()));
+    }
+
+    let (body, check) = imei.as_bytes().split_at(IMEI_LEN - 1);
+    if luhn_check_digit(body) != check[0] - b'0' {
+        return Err(ImeiError::InvalidCheckDigit(imei.to_owned()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_luhn_check_digit() {
+        assert_eq!(luhn_check_digit(b"49015420323751"), 8);
+        assert_eq!(luhn_check_digit(b"35145120840121"), 6);
+        assert_eq!(luhn_check_digit(b"7992739871"), 3);
+    }
+
+    #[test]
+    fn test_validate_imei() {
+        assert_eq!(validate_imei("490154203237518"), Ok(()));
+        assert_eq!(validate_imei("490154203237517"),
+                   Err(ImeiError::InvalidCheckDigit("490154203237517".to_owned())));
+        assert_eq!(validate_imei("49015420323751"),
+                   Err(ImeiError::InvalidLength("49015420323751".to_owned())));
+        assert_eq!(validate_imei("49015420323751a"),
+                   Err(ImeiError::InvalidLength("49015420323751a".to_owned())));
+    }
+
+    #[test]
+    fn test_imei_from_prefix() {
+        for prefix in ["35145120", "3", "35145120840121"] {
+            let imei = imei_from_prefix(prefix).unwrap();
+            assert!(imei.starts_with(prefix));
+            assert_eq!(validate_imei(&imei), Ok(()));
+        }
+
+        assert_eq!(imei_from_prefix("351451208401212"),
+                   Err(ImeiError::InvalidPrefix("351451208401212".to_owned())));
+        assert_eq!(imei_from_prefix(""), Err(ImeiError::InvalidPrefix("".to_owned())));
+        assert_eq!(imei_from_prefix("3514x"), Err(ImeiError::InvalidPrefix("3514x".to_owned())));
+    }
+}
@@ -1,4 +1,13 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "cassette")]
+pub mod cassette;
 pub mod crypto;
 pub mod fus;
+pub mod imei;
+pub mod profile;
+pub mod protocol;
 pub mod range;
+pub mod retry;
+pub mod transport;
 pub mod version;
@@ -0,0 +1,53 @@
+/// Client identity presented to FUS in the XML requests. FUS may answer
+/// differently depending on which client it thinks it is talking to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientProfile {
+    /// `CLIENT_PRODUCT` value in inform requests
+    pub client_product: String,
+    /// `ACCESS_MODE` value in inform requests
+    pub access_mode: String,
+    /// `FUSHdr/ProtoVer` value in all requests
+    pub proto_ver: String,
+    /// `FUSHdr/SessionID` value in all requests
+    pub session_id: String,
+    /// `FUSHdr/MsgID` value in all requests
+    pub msg_id: String,
+    /// Additional `(name, value)` pairs appended to the `Put` block of inform
+    /// requests
+    pub extra_fields: Vec<(String, String)>,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        Self::smart_switch()
+    }
+}
+
+impl ClientProfile {
+    /// Profile matching Samsung Smart Switch. This is the default.
+    pub fn smart_switch() -> Self {
+        Self {
+            client_product: "Smart Switch".to_owned(),
+            access_mode: "2".to_owned(),
+            proto_ver: "1.0".to_owned(),
+            session_id: "0".to_owned(),
+            msg_id: "1".to_owned(),
+            extra_fields: vec![],
+        }
+    }
+
+    /// Profile matching the older Samsung Kies software.
+    pub fn kies() -> Self {
+        Self {
+            client_product: "Kies".to_owned(),
+            access_mode: "1".to_owned(),
+            ..Self::smart_switch()
+        }
+    }
+
+    /// Append a custom field to the `Put` block of inform requests.
+    pub fn field(mut self, name: &str, value: &str) -> Self {
+        self.extra_fields.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
@@ -0,0 +1,986 @@
+//! Transport-agnostic implementation of the FOTA and FUS protocols.
+//!
+//! [`FusProtocol`] produces [`FusRequest`] descriptions and consumes the
+//! responses, but never performs any I/O itself. This allows the protocol to
+//! be driven by any HTTP stack. The HTTP stack must persist cookies between
+//! FUS requests because FUS ties the nonce to the session cookie.
+//! [`crate::fus::FusClient`] drives the protocol with `reqwest`.
+
+use crate::{
+    crypto::{FusAes256, FusKeys},
+    fus::{
+        DeviceId, FirmwareInfo, FotaVersion, FusError, DOWNLOAD_BASE_URL, FOTA_BASE_URL,
+        FUS_BASE_URL, LAST_MODIFIED_FORMAT,
+    },
+    profile::ClientProfile,
+    version::FwVersion,
+};
+
+use std::{
+    borrow::Cow,
+    convert::TryInto,
+    fmt,
+    ops::Range,
+    path::Path,
+    str,
+};
+
+use base64::{
+    Engine,
+    engine::general_purpose::STANDARD,
+};
+use http::{
+    header::{AUTHORIZATION, CONTENT_LENGTH, RANGE},
+    Method, Request, Response, StatusCode,
+};
+use log::debug;
+use time::PrimitiveDateTime;
+use xmltree::{Element, XMLNode};
+
+const NON_UTF8_MSG: &str = "[Non-UTF-8 data]";
+/// Version components sent when asking FUS for the latest version
+const FUS_PROBE_VERSION: &str = "0";
+
+/// Description of an HTTP request to send to the FOTA, FUS, or download
+/// server.
+pub type FusRequest = Request<Vec<u8>>;
+
+pub(crate) fn to_utf8_or_error_string(data: &[u8]) -> &str {
+    str::from_utf8(data).unwrap_or(NON_UTF8_MSG)
+}
+
+/// A type representing the Authorization field for FUS requests.
+#[derive(Debug)]
+struct Authorization {
+    pub nonce: String,
+    pub signature: String,
+    pub nc: String,
+    pub type_: String,
+    pub realm: String,
+    pub newauth: bool,
+}
+
+impl Authorization {
+    /// Construct a new instance with no component fields set and the new auth
+    /// mechanism enabled. Same as [`Self::default()`].
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Construct a new instance with the specified nonce signature and the new
+    /// auth mechanism enabled.
+    fn with_signature(signature: &str) -> Self {
+        Self {
+            nonce: Default::default(),
+            signature: signature.to_string(),
+            nc: Default::default(),
+            type_: Default::default(),
+            realm: Default::default(),
+            newauth: true,
+        }
+    }
+}
+
+impl Default for Authorization {
+    fn default() -> Self {
+        Self {
+            nonce: Default::default(),
+            signature: Default::default(),
+            nc: Default::default(),
+            type_: Default::default(),
+            realm: Default::default(),
+            // We do not support the legacy auth mechanism (unencrypted nonces)
+            // so make the new mechanism the default
+            newauth: true,
+        }
+    }
+}
+
+impl fmt::Display for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FUS nonce=\"{}\", signature=\"{}\", nc=\"{}\", type=\"{}\", realm=\"{}\", newauth=\"{}\"",
+            self.nonce,
+            self.signature,
+            self.nc,
+            self.type_,
+            self.realm,
+            u8::from(self.newauth),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum LogicCheckType<'a> {
+    Data(&'a [u8]),
+    Filename(&'a str),
+}
+
+/// A type representing a FUS nonce value.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Nonce {
+    // The official implementation tries to convert the AES flexible key from
+    // the platform string encoding to UTF-8 into a 33-byte NULL-terminated
+    // buffer. It never checks the return value, but relies on the data being
+    // written to the buffer. We can reasonably assume that the key is 32 bytes,
+    // meaning the nonce must be at most 16 bytes. Many other functions, such as
+    // one for computing the <LOGIC_CHECK> value expect the nonce to be at least
+    // 16 bytes, so we can conclude that it must be exactly 16 bytes.
+    data: [u8; 16],
+}
+
+impl Nonce {
+    /// Create instance from a byte slice containing the nonce.
+    /// [`FusError::NonceInvalidSize`] is returned if the slice is not 16 bytes.
+    pub fn from_slice(data: &[u8]) -> Result<Self, FusError> {
+        Ok(Self {
+            data: data.try_into().map_err(|_| FusError::NonceInvalidSize)?,
+        })
+    }
+
+    /// Get byte slice containing the nonce. The slice is guaranteed to always
+    /// be 16 bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Create instance from a fixed-key-encrypted nonce value.
+    pub fn from_encrypted(keys: &FusKeys, data: &[u8]) -> Result<Self, FusError> {
+        let decoded = STANDARD.decode(data)?;
+        let plaintext = FusAes256::new(&keys.fixed_key).decrypt(&decoded)?;
+        Self::from_slice(&plaintext)
+    }
+
+    /// Convert nonce to fixed-key-encrypted nonce.
+    pub fn to_encrypted(self, keys: &FusKeys) -> String {
+        STANDARD.encode(FusAes256::new(&keys.fixed_key).encrypt(&self.data))
+    }
+
+    /// Get the nonce signature to be used in the Authorization header for FUS
+    /// requests.
+    fn to_signature(self, keys: &FusKeys) -> String {
+        let key = keys.get_flexible_key(self.as_slice());
+        let ciphertext = FusAes256::new(&key).encrypt(self.as_slice());
+
+        STANDARD.encode(ciphertext)
+    }
+
+    /// Get full Authorization header value containing the nonce signature.
+    fn to_authorization(self, keys: &FusKeys) -> Authorization {
+        Authorization::with_signature(&self.to_signature(keys))
+    }
+
+    /// Get the scrambled nonce value to be used in the `<LOGIC_CHECK>` XML tag
+    /// of FUS requests.
+    pub(crate) fn to_logic_check(self, lc_type: LogicCheckType) -> String {
+        match lc_type {
+            LogicCheckType::Data(data) => {
+                if data.is_empty() {
+                    return String::new();
+                }
+
+                self.as_slice().iter()
+                    .map(|c| data[(*c as usize & 0xf) % data.len()] as char)
+                    .collect()
+            }
+            LogicCheckType::Filename(filename) => {
+                let mut data = filename.as_bytes();
+
+                if let Some(n) = data.iter().position(|x| *x == b'.') {
+                    data = &data[..n];
+                }
+                if data.len() > 16 {
+                    data = &data[data.len() - 16..];
+                }
+
+                self.to_logic_check(LogicCheckType::Data(data))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Nonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Intentionally keep error text at 16 bytes
+        write!(f, "{}", to_utf8_or_error_string(&self.data))
+    }
+}
+
+/// A download that FUS has been informed of via
+/// [`FusProtocol::start_download_request`].
+#[derive(Clone, Debug)]
+pub struct DownloadSession {
+    nonce: Nonce,
+    url: String,
+}
+
+/// State of a FUS session. This holds the current nonce, which FUS replaces
+/// with every response, and builds requests that are signed with it.
+///
+/// Requests that require a nonce fail with [`FusError::NonceNotFound`] until
+/// the response to [`Self::nonce_request`] has been passed to
+/// [`Self::handle_nonce_response`].
+#[derive(Clone)]
+pub struct FusProtocol {
+    keys: FusKeys,
+    profile: ClientProfile,
+    fota_base_url: String,
+    fus_base_url: String,
+    download_base_url: String,
+    nonce: Option<Nonce>,
+}
+
+impl FusProtocol {
+    /// Create a new protocol state with the default base URLs. Use
+    /// [`crate::fus::FusClientBuilder::build_protocol`] to customize them.
+    pub fn new(keys: FusKeys, profile: ClientProfile) -> Self {
+        Self::with_base_urls(keys, profile, FOTA_BASE_URL, FUS_BASE_URL, DOWNLOAD_BASE_URL)
+    }
+
+    pub(crate) fn with_base_urls(
+        keys: FusKeys,
+        profile: ClientProfile,
+        fota_base_url: &str,
+        fus_base_url: &str,
+        download_base_url: &str,
+    ) -> Self {
+        Self {
+            keys,
+            profile,
+            fota_base_url: fota_base_url.to_owned(),
+            fus_base_url: fus_base_url.to_owned(),
+            download_base_url: download_base_url.to_owned(),
+            nonce: None,
+        }
+    }
+
+    /// Whether a nonce is available for signing requests.
+    pub fn has_nonce(&self) -> bool {
+        self.nonce.is_some()
+    }
+
+    /// Discard the current nonce so that the next requests must start a new
+    /// session.
+    pub fn clear_nonce(&mut self) {
+        self.nonce = None;
+    }
+
+    fn current_nonce(&self) -> Result<Nonce, FusError> {
+        self.nonce.ok_or(FusError::NonceNotFound)
+    }
+
+    /// Build a request for the FOTA `version.xml` document of a given model
+    /// number and CSC region code.
+    pub fn fota_request(&self, model: &str, region: &str) -> Result<FusRequest, FusError> {
+        let url = format!("{}/firmware/{region}/{model}/version.xml", self.fota_base_url);
+        debug!("FOTA URL: {url}");
+
+        Ok(Request::get(url).body(vec![])?)
+    }
+
+    /// Parse a FOTA `version.xml` response.
+    fn parse_fota_response<B: AsRef<[u8]>>(response: &Response<B>) -> Result<Element, FusError> {
+        match response.status() {
+            StatusCode::OK => {}
+            // The FOTA server returns 403 when the page is not found
+            StatusCode::FORBIDDEN => return Err(FusError::FirmwareNotFound),
+            s => return Err(FusError::BadHttpResponse(StatusCode::OK, s)),
+        }
+
+        let data = response.body().as_ref();
+        debug!("FOTA response: {:?}", to_utf8_or_error_string(data));
+
+        Ok(Element::parse(data)?)
+    }
+
+    /// Get the latest firmware version from the response to
+    /// [`Self::fota_request`].
+    pub fn parse_latest_version<B: AsRef<[u8]>>(
+        &self,
+        response: &Response<B>,
+    ) -> Result<FwVersion, FusError> {
+        let root = Self::parse_fota_response(response)?;
+        let version = get_elem_text(&root, &["firmware", "version", "latest"])
+            .ok_or(FusError::FirmwareNotFound)?;
+
+        Ok(version.parse()?)
+    }
+
+    /// Get every firmware version from the response to
+    /// [`Self::fota_request`]. The latest version comes first, followed by the
+    /// older versions in the order that the server returns them.
+    pub fn parse_version_history<B: AsRef<[u8]>>(
+        &self,
+        response: &Response<B>,
+    ) -> Result<Vec<FotaVersion>, FusError> {
+        let root = Self::parse_fota_response(response)?;
+        parse_version_history(&root)
+    }
+
+    /// Build a request for generating a new nonce.
+    pub fn nonce_request(&self) -> Result<FusRequest, FusError> {
+        let url = format!("{}/NF_DownloadGenerateNonce.do", self.fus_base_url);
+        debug!("Requesting nonce from: {url}");
+
+        Ok(Request::post(url)
+            .header(AUTHORIZATION, Authorization::new().to_string())
+            .header(CONTENT_LENGTH, 0)
+            .body(vec![])?)
+    }
+
+    /// Store the nonce from the response to [`Self::nonce_request`].
+    pub fn handle_nonce_response<B>(&mut self, response: &Response<B>) -> Result<(), FusError> {
+        self.check_fus_response(response, StatusCode::OK)?;
+        self.current_nonce()?;
+        Ok(())
+    }
+
+    /// Return an error if the FUS response does not have the expected status.
+    /// If a NONCE header exists, regardless of the status code, then it is
+    /// saved for use with the next request.
+    fn check_fus_response<B>(
+        &mut self,
+        response: &Response<B>,
+        expected: StatusCode,
+    ) -> Result<(), FusError> {
+        self.nonce = response.headers().get("NONCE")
+            .and_then(|x| Nonce::from_encrypted(&self.keys, x.as_bytes()).ok());
+
+        check_status(response, expected)
+    }
+
+    /// Build a FUS request with the Authorization header for the specified
+    /// nonce.
+    fn fus_request(
+        &self,
+        method: Method,
+        url: &str,
+        nonce: Nonce,
+        auth_include_nonce: bool,
+    ) -> http::request::Builder {
+        let mut auth = nonce.to_authorization(&self.keys);
+        if auth_include_nonce {
+            auth.nonce = nonce.to_encrypted(&self.keys);
+        }
+
+        Request::builder()
+            .method(method)
+            .uri(url)
+            .header(AUTHORIZATION, auth.to_string())
+    }
+
+    /// Build a FUS request with an XML body.
+    fn fus_xml_request(&self, url: &str, body: &Element, nonce: Nonce) -> Result<FusRequest, FusError> {
+        debug!("FUS URL: {url}");
+
+        let mut buf = vec![];
+        body.write(&mut buf)?;
+
+        debug!("FUS request: {:?}", to_utf8_or_error_string(&buf));
+
+        Ok(self.fus_request(Method::POST, url, nonce, false).body(buf)?)
+    }
+
+    /// Parse the XML body of a FUS response. Unlike
+    /// [`Self::parse_fus_xml_response`], the FUS status code is not checked.
+    fn parse_fus_xml_response_unchecked<B: AsRef<[u8]>>(
+        &mut self,
+        response: &Response<B>,
+    ) -> Result<Element, FusError> {
+        self.check_fus_response(response, StatusCode::OK)?;
+
+        let data = response.body().as_ref();
+        debug!("FUS response: {:?}", to_utf8_or_error_string(data));
+
+        Ok(Element::parse(data)?)
+    }
+
+    /// Parse the XML body of a FUS response and interpret the FUS status
+    /// code.
+    fn parse_fus_xml_response<B: AsRef<[u8]>>(
+        &mut self,
+        response: &Response<B>,
+    ) -> Result<Element, FusError> {
+        let root = self.parse_fus_xml_response_unchecked(response)?;
+        check_fus_status(&root)?;
+
+        Ok(root)
+    }
+
+    /// Build an inform request for querying the latest firmware version from
+    /// FUS instead of FOTA. This works for regions where FOTA has no data,
+    /// like `ATT` or `VZW`.
+    ///
+    /// FUS reports the latest version in the `LATEST_FW_VERSION` result of an
+    /// inform request, even if the version in the request does not exist, so
+    /// a placeholder version is sent. For regions where FUS requires a device
+    /// identifier, `device_id` must be specified.
+    pub fn latest_version_request(
+        &self,
+        model: &str,
+        region: &str,
+        factory: bool,
+        device_id: Option<&DeviceId>,
+    ) -> Result<FusRequest, FusError> {
+        let probe = FwVersion::new(FUS_PROBE_VERSION, FUS_PROBE_VERSION, None, None);
+        self.firmware_info_request(model, region, &probe, factory, device_id)
+    }
+
+    /// Get the latest firmware version from the response to
+    /// [`Self::latest_version_request`].
+    pub fn parse_latest_version_fus<B: AsRef<[u8]>>(
+        &mut self,
+        response: &Response<B>,
+    ) -> Result<FwVersion, FusError> {
+        let root = self.parse_fus_xml_response_unchecked(response)?;
+
+        match get_elem_text(&root, &["FUSBody", "Results", "LATEST_FW_VERSION", "Data"]) {
+            Some(v) if !v.is_empty() => Ok(v.parse()?),
+            _ => {
+                // Report the FUS error if there is one
+                check_fus_status(&root)?;
+                Err(FusError::FirmwareNotFound)
+            }
+        }
+    }
+
+    /// Build an inform request for information about a firmware version for
+    /// a given model and region. For regions where FUS requires a device
+    /// identifier, `device_id` must be specified.
+    pub fn firmware_info_request(
+        &self,
+        model: &str,
+        region: &str,
+        version: &FwVersion,
+        factory: bool,
+        device_id: Option<&DeviceId>,
+    ) -> Result<FusRequest, FusError> {
+        let nonce = self.current_nonce()?;
+        let req_root = create_binary_inform_elem(
+            &self.profile, model, region, version, nonce, factory, device_id);
+
+        let url = format!("{}/NF_DownloadBinaryInform.do", self.fus_base_url);
+        self.fus_xml_request(&url, &req_root, nonce)
+    }
+
+    /// Get the firmware information from the response to
+    /// [`Self::firmware_info_request`].
+    pub fn parse_firmware_info<B: AsRef<[u8]>>(
+        &mut self,
+        response: &Response<B>,
+    ) -> Result<FirmwareInfo, FusError> {
+        let resp_root = self.parse_fus_xml_response(response)?;
+
+        macro_rules! get_value {
+            ($var:expr, $name:expr) => {
+                get_fus_field($var, $name)
+                    .ok_or(FusError::FusMissingField($name.to_owned()))?
+            }
+        }
+        macro_rules! get_string {
+            ($var:expr, $name:expr) => {
+                get_value!($var, $name).to_string()
+            }
+        }
+        macro_rules! get_parsed {
+            ($var:expr, $name:expr) => {
+                {
+                    let value = get_value!($var, $name);
+                    value.parse().map_err(|_| FusError::FusBadField(
+                        $name.to_owned(), value.to_string()))?
+                }
+            }
+        }
+
+        let binary_name = get_string!(&resp_root, "BINARY_NAME");
+        let filename = Path::new(&binary_name)
+            .file_name()
+            .ok_or_else(|| FusError::FusBadField("BINARY_NAME".to_owned(), binary_name.clone()))?
+            .to_str()
+            .unwrap() // Cannot panic
+            .to_owned();
+
+        Ok(FirmwareInfo {
+            version: get_parsed!(&resp_root, "CURRENT_DISPLAY_VERSION"),
+            version_name: get_string!(&resp_root, "CURRENT_OS_VERSION"),
+            platform: get_string!(&resp_root, "DEVICE_PLATFORM"),
+            model: get_string!(&resp_root, "DEVICE_MODEL_NAME"),
+            model_name: get_string!(&resp_root, "DEVICE_MODEL_DISPLAYNAME"),
+            model_type: get_parsed!(&resp_root, "DEVICE_MODEL_TYPE"),
+            region: get_string!(&resp_root, "DEVICE_LOCAL_CODE"),
+            path: get_string!(&resp_root, "MODEL_PATH"),
+            filename,
+            size: get_parsed!(&resp_root, "BINARY_BYTE_SIZE"),
+            crc: get_parsed!(&resp_root, "BINARY_CRC"),
+            last_modified: {
+                let value = get_value!(&resp_root, "LAST_MODIFIED");
+                PrimitiveDateTime::parse(&value, LAST_MODIFIED_FORMAT).map_err(|_| {
+                    FusError::FusBadField("LAST_MODIFIED".to_owned(), value.to_string())
+                })?
+            },
+            logic_option_home: get_value!(&resp_root, "LOGIC_OPTION_HOME") == "1",
+            logic_option_factory: get_value!(&resp_root, "LOGIC_OPTION_FACTORY") == "1",
+            logic_value_home: get_string!(&resp_root, "LOGIC_VALUE_HOME"),
+            logic_value_factory: get_string!(&resp_root, "LOGIC_VALUE_FACTORY"),
+            binary_nature: get_value!(&resp_root, "BINARY_NATURE") == "1",
+        })
+    }
+
+    /// Build an init request for informing FUS of the intention to download
+    /// the specified firmware. The returned session is only usable after the
+    /// response has been passed to [`Self::handle_start_download_response`].
+    pub fn start_download_request(
+        &self,
+        info: &FirmwareInfo,
+    ) -> Result<(FusRequest, DownloadSession), FusError> {
+        let nonce = self.current_nonce()?;
+        let req_root = create_binary_init_elem(&self.profile, info, nonce);
+
+        let url = format!("{}/NF_DownloadBinaryInitForMass.do", self.fus_base_url);
+        let request = self.fus_xml_request(&url, &req_root, nonce)?;
+
+        // Download binary. This intentionally does not URL-encode the query
+        // because FUS has been updated to return HTTP 405 if the requested
+        // filename is URL-encoded.
+        let url = format!(
+            "{}/NF_DownloadBinaryForMass.do?file={}{}",
+            self.download_base_url,
+            info.path,
+            info.filename,
+        );
+
+        Ok((request, DownloadSession { nonce, url }))
+    }
+
+    /// Check the response to [`Self::start_download_request`].
+    pub fn handle_start_download_response<B: AsRef<[u8]>>(
+        &mut self,
+        response: &Response<B>,
+    ) -> Result<(), FusError> {
+        self.parse_fus_xml_response(response)?;
+        Ok(())
+    }
+
+    /// Build a request for the specified byte range of the firmware that the
+    /// session was started for.
+    pub fn download_request(
+        &self,
+        session: &DownloadSession,
+        range: Range<u64>,
+    ) -> Result<FusRequest, FusError> {
+        debug!("Requesting bytes {}-{} from: {}", range.start, range.end, session.url);
+
+        Ok(self.fus_request(Method::GET, &session.url, session.nonce, true)
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end))
+            .body(vec![])?)
+    }
+
+    /// Check the status of the response to [`Self::download_request`]. The body
+    /// is the requested firmware data. Download requests use the session's
+    /// nonce, so the current nonce is left untouched. Otherwise, concurrent
+    /// downloads would clear the nonce for other requests.
+    pub fn handle_download_response<B>(&self, response: &Response<B>) -> Result<(), FusError> {
+        check_status(response, StatusCode::PARTIAL_CONTENT)
+    }
+}
+
+/// Return an error if the FUS response does not have the expected status.
+fn check_status<B>(response: &Response<B>, expected: StatusCode) -> Result<(), FusError> {
+    match response.status() {
+        s if s == expected => Ok(()),
+        StatusCode::UNAUTHORIZED => Err(FusError::FusUnauthorized),
+        s => Err(FusError::BadHttpResponse(expected, s)),
+    }
+}
+
+/// Parse all `<latest>` and `<upgrade>/<value>` entries from a FOTA
+/// `version.xml` document. Empty entries are skipped.
+fn parse_version_history(root: &Element) -> Result<Vec<FotaVersion>, FusError> {
+    let version_elem = root.get_child("firmware")
+        .and_then(|e| e.get_child("version"))
+        .ok_or(FusError::FirmwareNotFound)?;
+
+    let latest = version_elem.get_child("latest")
+        .map(|e| (e, true));
+    let upgrades = version_elem.get_child("upgrade")
+        .into_iter()
+        .flat_map(|e| &e.children)
+        .filter_map(|n| n.as_element())
+        .filter(|e| e.name == "value")
+        .map(|e| (e, false));
+
+    let mut result = vec![];
+
+    for (elem, is_latest) in latest.into_iter().chain(upgrades) {
+        let text = elem.get_text().unwrap_or_default();
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        result.push(FotaVersion {
+            version: text.trim().parse()?,
+            latest: is_latest,
+            attributes: elem.attributes.clone().into_iter().collect(),
+        });
+    }
+
+    if result.is_empty() {
+        return Err(FusError::FirmwareNotFound);
+    }
+
+    Ok(result)
+}
+
+/// Return an error if the FUS response's status code indicates failure.
+fn check_fus_status(root: &Element) -> Result<(), FusError> {
+    // HTTP 200, but there might still be a FUS error
+    let status = get_elem_text(root, &["FUSBody", "Results", "Status"])
+        .ok_or_else(|| FusError::FusBadResponse("Missing FUS status field".to_owned()))?;
+
+    if status != "200" {
+        return Err(FusError::from_fus_status(&status));
+    }
+
+    Ok(())
+}
+
+fn create_text_node(name: &str, text: &str) -> XMLNode {
+    let mut elem = Element::new(name);
+    elem.children.push(XMLNode::Text(text.to_owned()));
+    XMLNode::Element(elem)
+}
+
+fn create_data_node(name: &str, value: &str) -> XMLNode {
+    let mut elem = Element::new(name);
+    elem.children.push(create_text_node("Data", value));
+    XMLNode::Element(elem)
+}
+
+fn create_fus_hdr_node(profile: &ClientProfile) -> XMLNode {
+    let mut elem = Element::new("FUSHdr");
+    elem.children.push(create_text_node("ProtoVer", &profile.proto_ver));
+    elem.children.push(create_text_node("SessionID", &profile.session_id));
+    elem.children.push(create_text_node("MsgID", &profile.msg_id));
+    XMLNode::Element(elem)
+}
+
+fn create_binary_inform_elem(
+    profile: &ClientProfile,
+    model: &str,
+    region: &str,
+    version: &FwVersion,
+    nonce: Nonce,
+    binary_nature: bool,
+    device_id: Option<&DeviceId>,
+) -> Element {
+    use LogicCheckType::Data;
+
+    let mut fus_body = Element::new("FUSBody");
+
+    let mut put = Element::new("Put");
+    put.children.push(create_text_node("CmdID", "1"));
+    put.children.push(create_data_node("ACCESS_MODE", &profile.access_mode));
+    put.children.push(create_data_node("BINARY_NATURE",
+        if binary_nature { "1" } else { "0" }));
+    put.children.push(create_data_node("CLIENT_PRODUCT", &profile.client_product));
+    put.children.push(create_data_node("DEVICE_MODEL_NAME", model));
+    put.children.push(create_data_node("DEVICE_LOCAL_CODE", region));
+    put.children.push(create_data_node("DEVICE_FW_VERSION", &version.to_string()));
+    put.children.push(create_data_node("DEVICE_VER_COUNT", "4"));
+    put.children.push(create_data_node("DEVICE_PDA_CODE1_VERSION", &version.pda));
+    put.children.push(create_data_node("DEVICE_CSC_CODE2_VERSION", &version.csc));
+    put.children.push(create_data_node("DEVICE_PHONE_FONT_VERSION", &version.phone));
+    put.children.push(create_data_node("DEVICE_CONTENTS_DATA_VERSION", &version.data));
+    put.children.push(create_data_node("LOGIC_CHECK",
+        &nonce.to_logic_check(Data(version.to_string().as_bytes()))));
+    if let Some(id) = device_id {
+        put.children.push(create_data_node("DEVICE_IMEI_PUSH", id.as_str()));
+    }
+    for (name, value) in &profile.extra_fields {
+        put.children.push(create_data_node(name, value));
+    }
+    fus_body.children.push(XMLNode::Element(put));
+
+    let mut get = Element::new("Get");
+    get.children.push(create_text_node("CmdID", "2"));
+    get.children.push(create_text_node("LATEST_FW_VERSION", ""));
+    fus_body.children.push(XMLNode::Element(get));
+
+    let mut fus_msg = Element::new("FUSMsg");
+    fus_msg.children.push(create_fus_hdr_node(profile));
+    fus_msg.children.push(XMLNode::Element(fus_body));
+
+    fus_msg
+}
+
+fn create_binary_init_elem(
+    profile: &ClientProfile,
+    info: &FirmwareInfo,
+    nonce: Nonce,
+) -> Element {
+    use LogicCheckType::Filename;
+
+    let mut fus_body = Element::new("FUSBody");
+
+    let mut put = Element::new("Put");
+    put.children.push(create_text_node("CmdID", "1"));
+    put.children.push(create_data_node("DEVICE_MODEL_TYPE",
+        &info.model_type.to_string()));
+    put.children.push(create_data_node("BINARY_NATURE",
+        if info.binary_nature { "1" } else { "0" }));
+    put.children.push(create_data_node("DEVICE_LOCAL_CODE", &info.region));
+    put.children.push(create_data_node("BINARY_VERSION",
+        &info.version.to_string()));
+    put.children.push(create_data_node("BINARY_FILE_NAME", &info.filename));
+    put.children.push(create_data_node("LOGIC_CHECK",
+        &nonce.to_logic_check(Filename(&info.filename))));
+    fus_body.children.push(XMLNode::Element(put));
+
+    let mut get = Element::new("Get");
+    get.children.push(create_text_node("CmdID", "2"));
+    get.children.push(create_text_node("BINARY_EMERGENCY_OTP_SEND", ""));
+    fus_body.children.push(XMLNode::Element(get));
+
+    let mut fus_msg = Element::new("FUSMsg");
+    fus_msg.children.push(create_fus_hdr_node(profile));
+    fus_msg.children.push(XMLNode::Element(fus_body));
+
+    fus_msg
+}
+
+fn get_elem_text<'a>(elem: &'a Element, path: &[&str]) -> Option<Cow<'a, str>> {
+    let mut result = Some(elem);
+
+    for p in path {
+        result = result.and_then(|e| e.get_child(*p));
+    }
+
+    result.map(|e| e.get_text().unwrap_or(Cow::Borrowed("")))
+}
+
+fn get_fus_field<'a>(elem: &'a Element, field: &str) -> Option<Cow<'a, str>> {
+    get_elem_text(elem, &["FUSBody", "Put", field, "Data"])
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    fn test_keys() -> FusKeys {
+        FusKeys::new(
+            b"testing_testing_testing_testing_",
+            b"testing_testing_",
+        ).unwrap()
+    }
+
+    /// Build a response with the specified status, NONCE header, and body.
+    fn response(status: StatusCode, nonce: Option<&str>, body: &str) -> Response<Vec<u8>> {
+        let mut builder = Response::builder().status(status);
+        if let Some(n) = nonce {
+            builder = builder.header("NONCE", n);
+        }
+        builder.body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_authorization() {
+        assert_eq!(Authorization::new().to_string(),
+                   r#"FUS nonce="", signature="", nc="", type="", realm="", newauth="1""#);
+
+        assert_eq!(Authorization::with_signature("abc").to_string(),
+                   r#"FUS nonce="", signature="abc", nc="", type="", realm="", newauth="1""#);
+    }
+
+    #[test]
+    fn test_nonce() {
+        let keys = test_keys();
+
+        assert_matches!(Nonce::from_slice(b"testing_testing_"), Ok(_));
+        assert_matches!(Nonce::from_slice(b"testing_testing"),
+                        Err(FusError::NonceInvalidSize));
+        assert_matches!(Nonce::from_slice(b"testing_testing_t"),
+                        Err(FusError::NonceInvalidSize));
+
+        assert_eq!(Nonce::from_slice(b"testing_testing_").unwrap().to_string(),
+                   "testing_testing_");
+        assert_eq!(Nonce::from_slice(b"\xffesting_testing_").unwrap().to_string(),
+                   "[Non-UTF-8 data]");
+
+        assert_eq!(Nonce::from_slice(b"testing_testing_").unwrap().to_encrypted(&keys),
+                   "yrJiFOygpIxnq4nbWdT2NLk1Odu8m5+zcFKQL4PzV0A=");
+
+        assert_matches!(Nonce::from_encrypted(&keys, b"yrJiFOygpIxnq4nbWdT2NLk1Odu8m5+zcFKQL4PzV0A="),
+                        Ok(x) if x == Nonce::from_slice(b"testing_testing_").unwrap());
+    }
+
+    #[test]
+    fn test_nonce_signature() {
+        let keys = test_keys();
+
+        assert_eq!(Nonce::from_slice(b"testing_testing_").unwrap().to_signature(&keys),
+                   "9J2R5S8AAXs40SYA92cLHQfWDv/6w5cAeZkPOEDIFGw=");
+    }
+
+    #[test]
+    fn test_logic_check() {
+        use LogicCheckType::*;
+
+        let nonce = Nonce::from_slice(b"testing_testing_").unwrap();
+
+        assert_eq!(nonce.to_logic_check(Data(b"abc")), "bcabacbabcabacba");
+        assert_eq!(nonce.to_logic_check(Data(b"testing_testing_")), "intieg__intieg__");
+
+        assert_eq!(nonce.to_logic_check(Filename("abc")), "bcabacbabcabacba");
+        assert_eq!(nonce.to_logic_check(Filename("testing_testing_.enc4")), "intieg__intieg__");
+        assert_eq!(nonce.to_logic_check(Filename("testing_testing_testing_.enc4")), "intieg__intieg__");
+    }
+
+    #[test]
+    fn test_parse_version_history() {
+        let protocol = FusProtocol::new(test_keys(), ClientProfile::default());
+        let resp = response(StatusCode::OK, None, r#"
+            <versioninfo>
+                <firmware>
+                    <model>SM-T000</model>
+                    <cc>XAA</cc>
+                    <version>
+                        <latest o="13">A3/B3/A3/A3</latest>
+                        <upgrade>
+                            <value rcount="2" fwsize="100">A2/B2/A2/A2</value>
+                            <value />
+                            <value rcount="1">A1/B1//A1</value>
+                        </upgrade>
+                    </version>
+                </firmware>
+            </versioninfo>
+        "#);
+
+        let history = protocol.parse_version_history(&resp).unwrap();
+        assert_eq!(history.len(), 3);
+
+        assert_eq!(history[0].version, FwVersion::new("A3", "B3", None, None));
+        assert!(history[0].latest);
+        assert_eq!(history[0].attributes.get("o").map(|s| s.as_str()), Some("13"));
+
+        assert_eq!(history[1].version, FwVersion::new("A2", "B2", None, None));
+        assert!(!history[1].latest);
+        assert_eq!(history[1].attributes.len(), 2);
+
+        assert_eq!(history[2].version, FwVersion::new("A1", "B1", None, None));
+        assert!(!history[2].latest);
+
+        let resp = response(StatusCode::OK, None,
+            "<versioninfo><firmware><version><latest /></version></firmware></versioninfo>");
+        assert_matches!(protocol.parse_version_history(&resp), Err(FusError::FirmwareNotFound));
+
+        let resp = response(StatusCode::FORBIDDEN, None, "");
+        assert_matches!(protocol.parse_latest_version(&resp), Err(FusError::FirmwareNotFound));
+    }
+
+    #[test]
+    fn test_client_profile() {
+        let nonce = Nonce::from_slice(b"testing_testing_").unwrap();
+        let version = "A1/B1".parse().unwrap();
+
+        let root = create_binary_inform_elem(
+            &ClientProfile::default(), "SM-T000", "XAA", &version, nonce, false, None);
+        let text = |path: &[&str]| get_elem_text(&root, path).unwrap();
+        assert_eq!(text(&["FUSHdr", "ProtoVer"]), "1.0");
+        assert!(get_fus_field(&root, "DEVICE_IMEI_PUSH").is_none());
+        assert_eq!(text(&["FUSBody", "Put", "CLIENT_PRODUCT", "Data"]), "Smart Switch");
+        assert_eq!(text(&["FUSBody", "Put", "ACCESS_MODE", "Data"]), "2");
+
+        let profile = ClientProfile {
+            proto_ver: "2.0".to_owned(),
+            ..ClientProfile::kies()
+        }.field("CUSTOM", "value");
+        let device_id = DeviceId::Imei("490154203237518".to_owned());
+        let root = create_binary_inform_elem(
+            &profile, "SM-T000", "XAA", &version, nonce, false, Some(&device_id));
+        let text = |path: &[&str]| get_elem_text(&root, path).unwrap();
+        assert_eq!(text(&["FUSHdr", "ProtoVer"]), "2.0");
+        assert_eq!(text(&["FUSBody", "Put", "CLIENT_PRODUCT", "Data"]), "Kies");
+        assert_eq!(text(&["FUSBody", "Put", "ACCESS_MODE", "Data"]), "1");
+        assert_eq!(text(&["FUSBody", "Put", "CUSTOM", "Data"]), "value");
+        assert_eq!(text(&["FUSBody", "Put", "DEVICE_IMEI_PUSH", "Data"]), "490154203237518");
+    }
+
+    #[test]
+    fn test_session() {
+        let keys = test_keys();
+        let encrypted = Nonce::from_slice(b"testing_testing_").unwrap().to_encrypted(&keys);
+        let auth = format!("{}", Authorization::with_signature(
+            "9J2R5S8AAXs40SYA92cLHQfWDv/6w5cAeZkPOEDIFGw="));
+        let version = "A1/B1".parse().unwrap();
+
+        let mut protocol = FusProtocol::new(keys, ClientProfile::default());
+        assert_matches!(protocol.firmware_info_request("SM-T000", "XAA", &version, false, None),
+                        Err(FusError::NonceNotFound));
+
+        let req = protocol.nonce_request().unwrap();
+        assert_eq!(req.method(), Method::POST);
+        assert_eq!(req.uri(), "https://neofussvr.sslcs.cdngc.net/NF_DownloadGenerateNonce.do");
+        assert_matches!(protocol.handle_nonce_response(&response(StatusCode::OK, None, "")),
+                        Err(FusError::NonceNotFound));
+        protocol.handle_nonce_response(&response(StatusCode::OK, Some(&encrypted), "")).unwrap();
+        assert!(protocol.has_nonce());
+
+        let req = protocol.firmware_info_request("SM-T000", "XAA", &version, false, None).unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], auth.as_str());
+        let root = Element::parse(req.body().as_slice()).unwrap();
+        assert_eq!(get_fus_field(&root, "LOGIC_CHECK").unwrap(),
+                   Nonce::from_slice(b"testing_testing_").unwrap()
+                       .to_logic_check(LogicCheckType::Data(b"A1/B1/A1/A1")));
+
+        let resp = response(StatusCode::OK, Some(&encrypted), r#"
+            <FUSMsg><FUSBody>
+                <Results><Status>200</Status></Results>
+                <Put>
+                    <BINARY_NAME><Data>dir/SM-T000_A1_HOME.zip.enc4</Data></BINARY_NAME>
+                    <BINARY_BYTE_SIZE><Data>1008</Data></BINARY_BYTE_SIZE>
+                    <BINARY_CRC><Data>1234</Data></BINARY_CRC>
+                    <BINARY_NATURE><Data>0</Data></BINARY_NATURE>
+                    <CURRENT_DISPLAY_VERSION><Data>A1/B1</Data></CURRENT_DISPLAY_VERSION>
+                    <CURRENT_OS_VERSION><Data>T(Android 13)</Data></CURRENT_OS_VERSION>
+                    <DEVICE_PLATFORM><Data>Android</Data></DEVICE_PLATFORM>
+                    <DEVICE_MODEL_NAME><Data>SM-T000</Data></DEVICE_MODEL_NAME>
+                    <DEVICE_MODEL_DISPLAYNAME><Data>Test</Data></DEVICE_MODEL_DISPLAYNAME>
+                    <DEVICE_MODEL_TYPE><Data>9</Data></DEVICE_MODEL_TYPE>
+                    <DEVICE_LOCAL_CODE><Data>XAA</Data></DEVICE_LOCAL_CODE>
+                    <MODEL_PATH><Data>/neofus/9/</Data></MODEL_PATH>
+                    <LAST_MODIFIED><Data>20200226162005</Data></LAST_MODIFIED>
+                    <LOGIC_OPTION_HOME><Data>1</Data></LOGIC_OPTION_HOME>
+                    <LOGIC_OPTION_FACTORY><Data>0</Data></LOGIC_OPTION_FACTORY>
+                    <LOGIC_VALUE_HOME><Data>0123456789abcdef</Data></LOGIC_VALUE_HOME>
+                    <LOGIC_VALUE_FACTORY><Data></Data></LOGIC_VALUE_FACTORY>
+                </Put>
+            </FUSBody></FUSMsg>
+        "#);
+        let info = protocol.parse_firmware_info(&resp).unwrap();
+        assert_eq!(info.filename, "SM-T000_A1_HOME.zip.enc4");
+        assert_eq!(info.size, 1008);
+        assert!(info.logic_option_home);
+
+        let (req, session) = protocol.start_download_request(&info).unwrap();
+        assert_eq!(req.uri(), "https://neofussvr.sslcs.cdngc.net/NF_DownloadBinaryInitForMass.do");
+        protocol.handle_start_download_response(&response(StatusCode::OK, Some(&encrypted),
+            "<FUSMsg><FUSBody><Results><Status>200</Status></Results></FUSBody></FUSMsg>")).unwrap();
+
+        let req = protocol.download_request(&session, 16..31).unwrap();
+        assert_eq!(req.uri(), "http://cloud-neofussvr.sslcs.cdngc.net/NF_DownloadBinaryForMass.do\
+                               ?file=/neofus/9/SM-T000_A1_HOME.zip.enc4");
+        assert_eq!(req.headers()[RANGE], "bytes=16-31");
+        assert!(req.headers()[AUTHORIZATION].to_str().unwrap()
+            .starts_with(&format!("FUS nonce=\"{encrypted}\"")));
+
+        assert_matches!(protocol.handle_download_response(&response(StatusCode::OK, None, "")),
+                        Err(FusError::BadHttpResponse(StatusCode::PARTIAL_CONTENT, StatusCode::OK)));
+        // Download responses do not affect the nonce for FUS requests
+        assert!(protocol.has_nonce());
+        assert_matches!(protocol.handle_download_response(&response(StatusCode::UNAUTHORIZED, None, "")),
+                        Err(FusError::FusUnauthorized));
+    }
+}
@@ -24,11 +24,54 @@ pub fn split_range(
     }).collect()
 }
 
+/// Same as [`split_range`], but the boundaries between chunks are rounded down
+/// to a multiple of `align`. The start and end of `range` are kept as is.
+pub fn split_range_aligned(
+    range: Range<u64>,
+    n: u64,
+    min_chunk_size: Option<u64>,
+    align: u64,
+) -> Vec<Range<u64>> {
+    debug_assert!(align > 0);
+
+    let mut chunks = split_range(range, n, min_chunk_size);
+
+    for i in 1..chunks.len() {
+        let boundary = cmp::max(chunks[i].start / align * align, chunks[i - 1].start);
+        chunks[i - 1].end = boundary;
+        chunks[i].start = boundary;
+    }
+
+    chunks.retain(|r| r.start < r.end);
+    chunks
+}
+
+/// Get the parts of `bounds` that are not covered by `ranges`. The ranges must
+/// be sorted and must not overlap.
+pub fn complement_ranges(ranges: &[Range<u64>], bounds: Range<u64>) -> Vec<Range<u64>> {
+    let mut result = vec![];
+    let mut start = bounds.start;
+
+    for r in ranges {
+        if r.start > start {
+            result.push(start..r.start);
+        }
+        start = cmp::max(start, r.end);
+    }
+
+    if start < bounds.end {
+        result.push(start..bounds.end);
+    }
+
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::single_range_in_vec_init)]
     fn test_split_range() {
         // Empty range should not be split into anything
         assert_eq!(split_range(0..0, 1, None), &[]);
@@ -58,4 +101,30 @@ mod tests {
         // Non-zero starting point
         assert_eq!(split_range(1000..2000, 2, None), &[1000..1500, 1500..2000]);
     }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_split_range_aligned() {
+        assert_eq!(split_range_aligned(0..0, 1, None, 16), &[]);
+        assert_eq!(split_range_aligned(0..100, 1, None, 16), &[0..100]);
+        assert_eq!(split_range_aligned(0..100, 2, None, 16), &[0..48, 48..100]);
+        assert_eq!(split_range_aligned(0..100, 3, None, 16), &[0..32, 32..64, 64..100]);
+
+        // Chunks smaller than the alignment collapse into their neighbors
+        assert_eq!(split_range_aligned(0..40, 4, None, 16), &[0..16, 16..40]);
+        assert_eq!(split_range_aligned(0..10, 2, None, 16), &[0..10]);
+
+        // Unaligned starting point
+        assert_eq!(split_range_aligned(8..72, 2, None, 16), &[8..32, 32..72]);
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_complement_ranges() {
+        assert_eq!(complement_ranges(&[], 0..0), &[]);
+        assert_eq!(complement_ranges(&[], 0..10), &[0..10]);
+        assert_eq!(complement_ranges(&[0..10], 0..10), &[]);
+        assert_eq!(complement_ranges(&[2..4, 6..8], 0..10), &[0..2, 4..6, 8..10]);
+        assert_eq!(complement_ranges(&[0..4, 4..8], 0..10), &[8..10]);
+    }
 }
\ No newline at end of file
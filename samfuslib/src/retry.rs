@@ -0,0 +1,112 @@
+use std::{
+    cmp,
+    time::Duration,
+};
+
+/// How a failed request should be retried, based on the kind of error.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetryAction {
+    /// The error is caused by the request itself and will not go away by
+    /// retrying, like a nonexistent firmware version.
+    Fail,
+    /// The connection failed or stopped delivering data. Retry immediately.
+    Retry,
+    /// The server reported a temporary problem. Retry after a delay to avoid
+    /// making things worse.
+    Backoff,
+    /// The nonce or download session was rejected. Retry immediately with a
+    /// new FUS session.
+    NewSession,
+}
+
+/// Limits and delays for retrying failed requests.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries for a single download range
+    pub max_range_retries: u32,
+    /// Maximum number of retries across all ranges of a download. This also
+    /// limits the retries of requests that are not tied to a single range, like
+    /// starting a download session.
+    pub max_total_retries: u32,
+    /// Delay before the first retry after a [`RetryAction::Backoff`] error
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_range_retries: 3,
+            max_total_retries: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_range_retries: 0,
+            max_total_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Get the delay before retrying after the specified number of previous
+    /// failures (starting at 0). The delay doubles with each failure, up to
+    /// [`Self::max_backoff`], and a random amount of up to half of the delay
+    /// is subtracted so that parallel requests do not retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        let delay = cmp::min(self.initial_backoff.saturating_mul(factor), self.max_backoff);
+
+        delay.mul_f64(1.0 - fastrand::f64() / 2.0)
+    }
+
+    /// Get the delay before retrying after the specified number of previous
+    /// failures with an error of the specified kind, or `None` if the error
+    /// should not be retried.
+    pub fn delay_for(&self, action: RetryAction, attempt: u32) -> Option<Duration> {
+        match action {
+            RetryAction::Fail => None,
+            RetryAction::Retry | RetryAction::NewSession => Some(Duration::ZERO),
+            RetryAction::Backoff => Some(self.backoff(attempt)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        for (attempt, max) in [(0, 2), (1, 4), (2, 8), (3, 10), (100, 10)] {
+            let max = Duration::from_secs(max);
+
+            for _ in 0..100 {
+                let delay = policy.backoff(attempt);
+                assert!(delay >= max / 2 && delay <= max, "{}: {:?}", attempt, delay);
+            }
+        }
+    }
+
+    #[test]
+    fn test_delay_for() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(policy.delay_for(RetryAction::Fail, 0), None);
+        assert_eq!(policy.delay_for(RetryAction::Retry, 5), Some(Duration::ZERO));
+        assert_eq!(policy.delay_for(RetryAction::NewSession, 5), Some(Duration::ZERO));
+        assert!(policy.delay_for(RetryAction::Backoff, 0).unwrap() >= policy.initial_backoff / 2);
+    }
+}
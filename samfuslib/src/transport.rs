@@ -0,0 +1,116 @@
+//! HTTP stacks that [`crate::fus::FusClient`] can send its requests with.
+
+use crate::{
+    fus::FusError,
+    protocol::FusRequest,
+};
+
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
+use http::Response;
+use tokio_stream::StreamExt;
+
+/// Future returned by [`HttpTransport`] methods.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Response body that is received piece by piece.
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, FusError>> + Send>>;
+
+/// An HTTP stack for sending FOTA, FUS, and download requests.
+///
+/// Implementations must persist cookies between requests because FUS ties the
+/// nonce to the session cookie. Timeouts are applied by the client, so
+/// implementations do not need to handle them. Errors that are not specific
+/// to FUS should be reported as [`FusError::TransportError`].
+pub trait HttpTransport: Send + Sync {
+    /// Send a request and return the response as soon as the headers have
+    /// been received. The body is read from the returned stream.
+    fn send_streaming(&self, request: FusRequest)
+        -> BoxFuture<'_, Result<Response<BodyStream>, FusError>>;
+
+    /// Send a request and read the full response body. By default, this
+    /// collects the body from [`Self::send_streaming`].
+    fn send(&self, request: FusRequest) -> BoxFuture<'_, Result<Response<Bytes>, FusError>> {
+        Box::pin(async move {
+            let (parts, mut stream) = self.send_streaming(request).await?.into_parts();
+            let mut body = BytesMut::new();
+
+            while let Some(data) = stream.next().await {
+                body.extend_from_slice(&data?);
+            }
+
+            Ok(Response::from_parts(parts, body.freeze()))
+        })
+    }
+}
+
+impl<T: HttpTransport + ?Sized> HttpTransport for Arc<T> {
+    fn send_streaming(&self, request: FusRequest)
+            -> BoxFuture<'_, Result<Response<BodyStream>, FusError>> {
+        (**self).send_streaming(request)
+    }
+
+    fn send(&self, request: FusRequest) -> BoxFuture<'_, Result<Response<Bytes>, FusError>> {
+        (**self).send(request)
+    }
+}
+
+/// The default transport, which uses `reqwest`. Use
+/// [`crate::fus::FusClientBuilder::build_default_transport`] to create one
+/// with the TLS, proxy, and timeout options applied.
+#[derive(Clone, Debug)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    /// Create a transport from an existing `reqwest` client. The client must
+    /// have the cookie store enabled.
+    pub fn new(client: reqwest::Client) -> Self {
+        Self { client }
+    }
+
+    async fn execute(&self, request: FusRequest) -> Result<reqwest::Response, FusError> {
+        let request = reqwest::Request::try_from(request)?;
+        Ok(self.client.execute(request).await?)
+    }
+
+    /// Copy the status and headers of a reqwest response to a response with
+    /// the specified body.
+    fn to_http_response<B>(response: &reqwest::Response, body: B) -> Response<B> {
+        let mut result = Response::new(body);
+        *result.status_mut() = response.status();
+        *result.headers_mut() = response.headers().clone();
+        result
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn send_streaming(&self, request: FusRequest)
+            -> BoxFuture<'_, Result<Response<BodyStream>, FusError>> {
+        Box::pin(async move {
+            let r = self.execute(request).await?;
+            let head = Self::to_http_response(&r, ());
+            let stream: BodyStream = Box::pin(r.bytes_stream().map(|r| r.map_err(FusError::from)));
+
+            Ok(head.map(|_| stream))
+        })
+    }
+
+    fn send(&self, request: FusRequest) -> BoxFuture<'_, Result<Response<Bytes>, FusError>> {
+        Box::pin(async move {
+            let r = self.execute(request).await?;
+            let head = Self::to_http_response(&r, ());
+            let body = r.bytes().await?;
+
+            Ok(head.map(|_| body))
+        })
+    }
+}
@@ -1,8 +1,11 @@
 use std::{
+    cmp::Ordering,
     fmt,
     str::FromStr,
 };
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 
 /// A type representing the `<pda>/<csc>/<phone>/<data>` version string used by
@@ -29,6 +32,60 @@ impl FwVersion {
             data: data.map_or_else(|| pda.to_owned(), |s| s.to_owned()),
         }
     }
+
+    /// Parse a version string like [`FromStr`], but also require every
+    /// component to be a decodable build string.
+    pub fn parse_strict(s: &str) -> Result<Self, ParseFwVersionError> {
+        let version: Self = s.parse()?;
+
+        for component in [&version.pda, &version.csc, &version.phone, &version.data] {
+            component.parse::<BuildString>()?;
+        }
+
+        Ok(version)
+    }
+
+    /// Decode the PDA component, which determines the bootloader revision and
+    /// the release date of the firmware.
+    pub fn pda_build(&self) -> Result<BuildString, ParseFwVersionError> {
+        self.pda.parse()
+    }
+}
+
+/// Serialized as the `<pda>/<csc>/<phone>/<data>` string.
+#[cfg(feature = "serde")]
+impl Serialize for FwVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for FwVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Ord for FwVersion {
+    /// Versions are ordered by their decoded PDA build strings. This is only
+    /// meaningful for versions of the same model. Versions with a PDA that
+    /// cannot be decoded sort before all decodable versions. Ties are broken
+    /// by comparing the raw components.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.pda_build().ok().cmp(&other.pda_build().ok())
+            .then_with(|| self.pda.cmp(&other.pda))
+            .then_with(|| self.csc.cmp(&other.csc))
+            .then_with(|| self.phone.cmp(&other.phone))
+            .then_with(|| self.data.cmp(&other.data))
+    }
+}
+
+impl PartialOrd for FwVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl fmt::Display for FwVersion {
@@ -66,12 +123,160 @@ impl FromStr for FwVersion {
     }
 }
 
+/// A decoded Samsung build string, like the `G991BXXU5CVLL` in each component
+/// of a [`FwVersion`]. The layout is:
+///
+/// | Example | Description                                           |
+/// |---------|-------------------------------------------------------|
+/// | `G991B` | Model number without the `SM-` prefix                 |
+/// | `XXU`   | Region/carrier variant and build type                 |
+/// | `5`     | Bootloader (binary) revision (`0`-`9`, then `A`-`Z`)  |
+/// | `C`     | Major update letter (`A` is the first OS version)     |
+/// | `V`     | Year (`A` is 2001)                                    |
+/// | `L`     | Month (`A` is January)                                |
+/// | `L`     | Build sequence (`1`-`9`, then `A`-`Z`)                |
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BuildString {
+    /// Model number suffix (eg. `G991B`)
+    pub model: String,
+    /// Region/carrier variant and build type (eg. `XXU` or `SQU`)
+    pub variant: String,
+    /// Bootloader revision. Devices refuse to flash firmware with a lower
+    /// revision than what is currently installed.
+    pub bootloader: u8,
+    /// Major update letter (eg. `C` for the third major OS version)
+    pub major: char,
+    /// Year of the build
+    pub year: u16,
+    /// Month of the build (1-12)
+    pub month: u8,
+    /// Build sequence number within the month
+    pub build: u8,
+}
+
+impl BuildString {
+    /// Length of the encoded fields following the model number.
+    const SUFFIX_LEN: usize = 8;
+}
+
+/// Decode a `0`-`9`, `A`-`Z` character into 0-35.
+fn decode_base36(c: char) -> Option<u8> {
+    c.to_digit(36)
+        .filter(|_| !c.is_ascii_lowercase())
+        .map(|d| d as u8)
+}
+
+/// Encode 0-35 into a `0`-`9`, `A`-`Z` character.
+fn encode_base36(n: u8) -> Option<char> {
+    std::char::from_digit(n.into(), 36).map(|c| c.to_ascii_uppercase())
+}
+
+impl FromStr for BuildString {
+    type Err = ParseFwVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars: Vec<char> = s.chars().collect();
+        if chars.len() <= Self::SUFFIX_LEN {
+            return Err(ParseFwVersionError::BuildTooShort(s.to_owned()));
+        }
+
+        let rest = chars.split_off(chars.len() - Self::SUFFIX_LEN);
+        let model: String = chars.into_iter().collect();
+        let invalid = |field, value| ParseFwVersionError::InvalidBuildChar {
+            build: s.to_owned(),
+            field,
+            value,
+        };
+
+        let bootloader = decode_base36(rest[3])
+            .ok_or_else(|| invalid("bootloader revision", rest[3]))?;
+        let major = Some(rest[4])
+            .filter(|c| c.is_ascii_uppercase())
+            .ok_or_else(|| invalid("major update", rest[4]))?;
+        let year = Some(rest[5])
+            .filter(|c| c.is_ascii_uppercase())
+            .map(|c| 2001 + (c as u16 - 'A' as u16))
+            .ok_or_else(|| invalid("year", rest[5]))?;
+        let month = Some(rest[6])
+            .filter(|c| ('A'..='L').contains(c))
+            .map(|c| 1 + (c as u8 - b'A'))
+            .ok_or_else(|| invalid("month", rest[6]))?;
+        let build = decode_base36(rest[7])
+            .ok_or_else(|| invalid("build sequence", rest[7]))?;
+
+        Ok(Self {
+            model,
+            variant: rest[..3].iter().collect(),
+            bootloader,
+            major,
+            year,
+            month,
+            build,
+        })
+    }
+}
+
+/// Formatting fails with [`fmt::Error`] if a field is outside of the range
+/// that can be represented in a build string.
+impl fmt::Display for BuildString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bootloader = encode_base36(self.bootloader).ok_or(fmt::Error)?;
+        let year = self.year.checked_sub(2001)
+            .filter(|y| *y < 26)
+            .map(|y| (b'A' + y as u8) as char)
+            .ok_or(fmt::Error)?;
+        let month = Some(self.month)
+            .filter(|m| (1..=12).contains(m))
+            .map(|m| (b'A' + m - 1) as char)
+            .ok_or(fmt::Error)?;
+        let build = encode_base36(self.build).ok_or(fmt::Error)?;
+
+        write!(
+            f,
+            "{}{}{}{}{}{}{}",
+            self.model,
+            self.variant,
+            bootloader,
+            self.major,
+            year,
+            month,
+            build,
+        )
+    }
+}
+
+impl Ord for BuildString {
+    /// Builds are ordered by bootloader revision, major update, date, and
+    /// build sequence, in that order. This is only meaningful for builds of
+    /// the same model. The remaining fields only break ties.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.bootloader, self.major, self.year, self.month, self.build)
+            .cmp(&(other.bootloader, other.major, other.year, other.month, other.build))
+            .then_with(|| self.model.cmp(&other.model))
+            .then_with(|| self.variant.cmp(&other.variant))
+    }
+}
+
+impl PartialOrd for BuildString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ParseFwVersionError {
     #[error("Too few fields (<2) in version string")]
     TooFewFields,
     #[error("Too many fields (>4) in version string")]
     TooManyFields,
+    #[error("Build string is too short to decode: {0:?}")]
+    BuildTooShort(String),
+    #[error("Invalid {field} character {value:?} in build string: {build:?}")]
+    InvalidBuildChar {
+        build: String,
+        field: &'static str,
+        value: char,
+    },
 }
 
 #[cfg(test)]
@@ -112,4 +317,94 @@ mod tests {
         let result: Result<FwVersion, _> = "a/b/c/d/e".parse();
         assert_matches!(result, Err(ParseFwVersionError::TooManyFields));
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let version = FwVersion::new("a", "b", Some("c"), None);
+        let json = serde_json::to_string(&version).unwrap();
+        assert_eq!(json, r#""a/b/c/a""#);
+        assert_eq!(serde_json::from_str::<FwVersion>(&json).unwrap(), version);
+
+        assert!(serde_json::from_str::<FwVersion>(r#""a""#).is_err());
+    }
+
+    #[test]
+    fn test_parse_strict() {
+        let result = FwVersion::parse_strict("G991BXXU5CVLL/G991BOXM5CVLL");
+        assert_matches!(result, Ok(_));
+
+        let result = FwVersion::parse_strict("G991BXXU5CVLL/G991BOXM5CVLL/a/G991BXXU5CVLL");
+        assert_matches!(result, Err(ParseFwVersionError::BuildTooShort(s)) if s == "a");
+
+        let result = FwVersion::parse_strict("a");
+        assert_matches!(result, Err(ParseFwVersionError::TooFewFields));
+    }
+
+    #[test]
+    fn test_build_string() {
+        let build: BuildString = "G991BXXU5CVLL".parse().unwrap();
+        assert_eq!(build, BuildString {
+            model: "G991B".to_owned(),
+            variant: "XXU".to_owned(),
+            bootloader: 5,
+            major: 'C',
+            year: 2022,
+            month: 12,
+            build: 21,
+        });
+        assert_eq!(build.to_string(), "G991BXXU5CVLL");
+
+        let build: BuildString = "N986USQUBATGM".parse().unwrap();
+        assert_eq!(build.model, "N986U");
+        assert_eq!(build.variant, "SQU");
+        assert_eq!(build.bootloader, 11);
+        assert_eq!((build.major, build.year, build.month, build.build), ('A', 2020, 7, 22));
+        assert_eq!(build.to_string(), "N986USQUBATGM");
+
+        assert_matches!("XXU5CVLL".parse::<BuildString>(),
+                        Err(ParseFwVersionError::BuildTooShort(_)));
+        assert_matches!("G991BXXU5CVML".parse::<BuildString>(),
+                        Err(ParseFwVersionError::InvalidBuildChar { field: "month", value: 'M', .. }));
+        assert_matches!("G991BXXU5C1LL".parse::<BuildString>(),
+                        Err(ParseFwVersionError::InvalidBuildChar { field: "year", value: '1', .. }));
+        assert_matches!("G991BXXUaCVLL".parse::<BuildString>(),
+                        Err(ParseFwVersionError::InvalidBuildChar { field: "bootloader revision", .. }));
+        assert_matches!("G991BXXU5CVL-".parse::<BuildString>(),
+                        Err(ParseFwVersionError::InvalidBuildChar { field: "build sequence", .. }));
+
+        // Out of range fields cannot be formatted
+        let format = |b: &BuildString| {
+            use std::fmt::Write;
+            let mut s = String::new();
+            write!(s, "{}", b).map(|_| s)
+        };
+        let valid: BuildString = "G991BXXU5CVLL".parse().unwrap();
+        assert_eq!(format(&valid).unwrap(), "G991BXXU5CVLL");
+        assert!(format(&BuildString { year: 2000, ..valid.clone() }).is_err());
+        assert!(format(&BuildString { year: 2027, ..valid.clone() }).is_err());
+        assert!(format(&BuildString { month: 0, ..valid.clone() }).is_err());
+        assert!(format(&BuildString { month: 13, ..valid.clone() }).is_err());
+        assert!(format(&BuildString { bootloader: 36, ..valid.clone() }).is_err());
+        assert!(format(&BuildString { build: 255, ..valid }).is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let parse = |s: &str| s.parse::<FwVersion>().unwrap();
+
+        // Build sequence
+        assert!(parse("G991BXXU5CVLL/a") > parse("G991BXXU5CVLK/a"));
+        // Month and year
+        assert!(parse("G991BXXU5CWAA/a") > parse("G991BXXU5CVLL/a"));
+        // Major update
+        assert!(parse("G991BXXU5DVAA/a") > parse("G991BXXU5CWLL/a"));
+        // Bootloader revision takes precedence over everything else
+        assert!(parse("G991BXXU6AAAA/a") > parse("G991BXXU5ZZLZ/a"));
+        // Undecodable versions sort first
+        assert!(parse("zzz/a") < parse("G991BXXU1AAA1/a"));
+        // Ties are broken by the raw components
+        assert!(parse("G991BXXU5CVLL/b") > parse("G991BXXU5CVLL/a"));
+        assert_eq!(parse("G991BXXU5CVLL/a").cmp(&parse("G991BXXU5CVLL/a")), Ordering::Equal);
+    }
 }
@@ -0,0 +1,122 @@
+use crc32fast::Hasher;
+use samfuslib::{
+    crypto::FusFileAes128,
+    fus::FirmwareInfo,
+    version::FwVersion,
+};
+use time::{macros::datetime, PrimitiveDateTime};
+
+/// A firmware image served by [`crate::MockServer`]. The plaintext data is
+/// encrypted with the same key derivation that real FUS firmware uses, so that
+/// clients can decrypt and CRC32-validate it as usual.
+pub struct MockFirmware {
+    pub(crate) info: FirmwareInfo,
+    pub(crate) plaintext: Vec<u8>,
+    pub(crate) ciphertext: Vec<u8>,
+}
+
+impl MockFirmware {
+    /// Create a new home firmware image using v2 (`.enc2`) encryption. The
+    /// plaintext is zero padded to a multiple of the AES block size.
+    pub fn new(model: &str, region: &str, version: FwVersion, plaintext: &[u8]) -> Self {
+        let mut s = Self {
+            info: FirmwareInfo {
+                version,
+                version_name: "T(Android 13)".to_owned(),
+                platform: "Android".to_owned(),
+                model: model.to_owned(),
+                model_name: "Mock Device".to_owned(),
+                model_type: 9,
+                region: region.to_owned(),
+                path: "/neofus/9/".to_owned(),
+                filename: String::new(),
+                size: 0,
+                crc: 0,
+                last_modified: datetime!(2020-02-26 16:20:05),
+                logic_option_home: false,
+                logic_option_factory: false,
+                logic_value_home: String::new(),
+                logic_value_factory: String::new(),
+                binary_nature: false,
+            },
+            plaintext: plaintext.to_vec(),
+            ciphertext: vec![],
+        };
+
+        let padded_len = s.plaintext.len().next_multiple_of(16);
+        s.plaintext.resize(padded_len, 0);
+        s.update();
+
+        s
+    }
+
+    /// Mark the image as a factory image instead of a home image.
+    pub fn factory(mut self, value: bool) -> Self {
+        self.info.binary_nature = value;
+        self.update();
+        self
+    }
+
+    /// Set the modification date reported by the server.
+    pub fn last_modified(mut self, value: PrimitiveDateTime) -> Self {
+        self.info.last_modified = value;
+        self
+    }
+
+    /// Use v4 (`.enc4`) encryption with the specified 16-byte logic value.
+    pub fn logic_value(mut self, value: &str) -> Self {
+        self.info.logic_option_home = true;
+        self.info.logic_option_factory = true;
+        self.info.logic_value_home = value.to_owned();
+        self.info.logic_value_factory = value.to_owned();
+        self.update();
+        self
+    }
+
+    /// Information that the server reports for this image.
+    pub fn info(&self) -> &FirmwareInfo {
+        &self.info
+    }
+
+    /// Decrypted firmware data.
+    pub fn plaintext(&self) -> &[u8] {
+        &self.plaintext
+    }
+
+    /// Encrypted firmware data, as served by the download endpoint.
+    pub fn ciphertext(&self) -> &[u8] {
+        &self.ciphertext
+    }
+
+    /// Recompute the filename, ciphertext, and checksum after a property
+    /// change.
+    fn update(&mut self) {
+        let new_logic = if self.info.binary_nature {
+            self.info.logic_option_factory
+        } else {
+            self.info.logic_option_home
+        };
+
+        self.info.filename = format!(
+            "{}_{}_{}.zip.{}",
+            self.info.model,
+            self.info.version.pda,
+            if self.info.binary_nature { "FAC" } else { "HOME" },
+            if new_logic { "enc4" } else { "enc2" },
+        );
+
+        // The key does not depend on the ciphertext, so it is always valid to
+        // compute here
+        let key = self.info.encryption_key()
+            .expect("Invalid logic value for mock firmware");
+
+        self.ciphertext = self.plaintext.clone();
+        FusFileAes128::new(&key).encrypt_in_place(&mut self.ciphertext).unwrap();
+
+        let mut hasher = Hasher::new();
+        hasher.update(&self.ciphertext);
+
+        self.info.size = self.ciphertext.len() as u64;
+        self.info.crc = hasher.finalize();
+    }
+}
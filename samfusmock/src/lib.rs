@@ -0,0 +1,5 @@
+mod firmware;
+mod server;
+
+pub use firmware::MockFirmware;
+pub use server::{MockServer, MockStats, MockTransport};
@@ -0,0 +1,1084 @@
+use std::{
+    borrow::Cow,
+    cmp,
+    collections::{HashMap, HashSet},
+    convert::{Infallible, TryInto},
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{
+    Engine,
+    engine::general_purpose::STANDARD,
+};
+use hyper::{
+    Body, Method, Request, Response, Server, StatusCode,
+    body::{self, Bytes},
+    header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+    service::{make_service_fn, service_fn},
+};
+use log::debug;
+use samfuslib::{
+    crypto::{FusAes256, FusKeys},
+    fus::{FusClientBuilder, FusError, LAST_MODIFIED_FORMAT},
+    protocol::FusRequest,
+    transport::{BodyStream, BoxFuture, HttpTransport},
+};
+use tokio::sync::oneshot;
+use tokio_stream::StreamExt;
+use xmltree::Element;
+
+use crate::firmware::MockFirmware;
+
+/// Size of each chunk of the download response body.
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+/// Base URL reported by servers that do not listen on a socket.
+const IN_MEMORY_BASE_URL: &str = "http://samfusmock.invalid";
+
+/// Number of requests received by each endpoint of [`MockServer`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MockStats {
+    /// `version.xml` requests to the FOTA server
+    pub fota: usize,
+    /// `NF_DownloadGenerateNonce.do` requests
+    pub nonce: usize,
+    /// `NF_DownloadBinaryInform.do` requests
+    pub inform: usize,
+    /// `NF_DownloadBinaryInitForMass.do` requests
+    pub init: usize,
+    /// `NF_DownloadBinaryForMass.do` requests
+    pub download: usize,
+}
+
+struct State {
+    keys: FusKeys,
+    firmware: Vec<MockFirmware>,
+    /// Every nonce that has been handed out
+    nonces: Vec<[u8; 16]>,
+    /// (nonce, filename) pairs that have completed the init request
+    initialized: HashSet<([u8; 16], String)>,
+    stats: MockStats,
+    /// Number of upcoming downloads that should fail with HTTP 500
+    fail_downloads: usize,
+    /// Number of upcoming downloads that should fail with HTTP 401 after
+    /// forgetting every download session
+    expire_sessions: usize,
+    /// Number of upcoming downloads that should be truncated and the number of
+    /// bytes after which they are truncated
+    truncate_downloads: (usize, u64),
+    /// Number of upcoming downloads that should stop sending data without
+    /// closing the connection and the number of bytes after which they stop
+    stall_downloads: (usize, u64),
+    /// Delay between each chunk of a download response body
+    download_delay: Duration,
+    /// Whether download responses omit the NONCE header
+    download_nonce_omitted: bool,
+    /// Regions that the FOTA server has no data for
+    fota_disabled: HashSet<String>,
+    /// Regions where inform requests fail without a `DEVICE_IMEI_PUSH` field
+    device_id_required: HashSet<String>,
+}
+
+impl State {
+    fn new_nonce(&mut self) -> [u8; 16] {
+        let mut nonce = [0u8; 16];
+        let value = format!("MOCKNONCE{:07}", self.nonces.len());
+        nonce.copy_from_slice(value.as_bytes());
+
+        self.nonces.push(nonce);
+        nonce
+    }
+
+    fn encrypt_nonce(&self, nonce: &[u8]) -> String {
+        STANDARD.encode(FusAes256::new(&self.keys.fixed_key).encrypt(nonce))
+    }
+
+    fn decrypt_nonce(&self, data: &str) -> Option<[u8; 16]> {
+        let decoded = STANDARD.decode(data).ok()?;
+        let plaintext = FusAes256::new(&self.keys.fixed_key).decrypt(&decoded).ok()?;
+        plaintext.as_slice().try_into().ok()
+    }
+
+    fn signature(&self, nonce: &[u8]) -> String {
+        let key = self.keys.get_flexible_key(nonce);
+        STANDARD.encode(FusAes256::new(&key).encrypt(nonce))
+    }
+
+    /// Find the issued nonce that matches the signature in the Authorization
+    /// header. If the header contains an encrypted nonce, it must match too.
+    fn authenticate<T>(&self, req: &Request<T>) -> Option<[u8; 16]> {
+        let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        let auth = parse_authorization(value);
+        let signature = auth.get("signature")?;
+
+        let nonce = match auth.get("nonce") {
+            Some(n) if !n.is_empty() => {
+                let nonce = self.decrypt_nonce(n)?;
+                if !self.nonces.contains(&nonce) {
+                    return None;
+                }
+                nonce
+            }
+            _ => *self.nonces.iter().find(|n| &self.signature(*n) == signature)?,
+        };
+
+        if &self.signature(&nonce) == signature {
+            Some(nonce)
+        } else {
+            None
+        }
+    }
+
+    fn find_firmware(&self, model: &str, region: &str, version: &str, factory: bool)
+            -> Option<&MockFirmware> {
+        self.firmware.iter().find(|f| {
+            f.info.model == model
+                && f.info.region == region
+                && f.info.version.to_string() == version
+                && f.info.binary_nature == factory
+        })
+    }
+
+    /// Versions available for a model and region, from oldest to newest.
+    fn versions(&self, model: &str, region: &str) -> Vec<String> {
+        let mut result: Vec<String> = vec![];
+
+        for f in &self.firmware {
+            let version = f.info.version.to_string();
+            if f.info.model == model && f.info.region == region && !result.contains(&version) {
+                result.push(version);
+            }
+        }
+
+        result
+    }
+}
+
+/// Local stand-in for the FUS and FOTA servers. It issues real encrypted
+/// nonces, validates Authorization signatures and `LOGIC_CHECK` values, and
+/// serves firmware encrypted the same way as on the real servers. The server
+/// shuts down when dropped.
+pub struct MockServer {
+    addr: Option<SocketAddr>,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Start a server on a random localhost port. The keys must match the ones
+    /// given to the FUS client. Must be called from within a tokio runtime.
+    pub async fn start(keys: FusKeys, firmware: Vec<MockFirmware>) -> Result<Self, hyper::Error> {
+        let state = Self::new_state(keys, firmware);
+
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req)))
+            }
+        });
+
+        let server = Server::try_bind(&([127, 0, 0, 1], 0).into())?
+            .serve(make_service);
+        let addr = server.local_addr();
+        let (tx, rx) = oneshot::channel();
+
+        tokio::spawn(server.with_graceful_shutdown(async {
+            rx.await.ok();
+        }));
+
+        debug!("Mock server listening on {addr}");
+
+        Ok(Self {
+            addr: Some(addr),
+            state,
+            shutdown: Some(tx),
+        })
+    }
+
+    /// Create a server that does not listen on a socket. Clients built with
+    /// [`Self::client_builder`] reach it through a [`MockTransport`] instead.
+    pub fn in_memory(keys: FusKeys, firmware: Vec<MockFirmware>) -> Self {
+        Self {
+            addr: None,
+            state: Self::new_state(keys, firmware),
+            shutdown: None,
+        }
+    }
+
+    fn new_state(keys: FusKeys, firmware: Vec<MockFirmware>) -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State {
+            keys,
+            firmware,
+            nonces: vec![],
+            initialized: HashSet::new(),
+            stats: MockStats::default(),
+            fail_downloads: 0,
+            expire_sessions: 0,
+            truncate_downloads: (0, 0),
+            stall_downloads: (0, 0),
+            download_delay: Duration::ZERO,
+            download_nonce_omitted: false,
+            fota_disabled: HashSet::new(),
+            device_id_required: HashSet::new(),
+        }))
+    }
+
+    /// Base URL that serves all of the FOTA, FUS, and download endpoints. For
+    /// in-memory servers, this is a placeholder that does not resolve.
+    pub fn base_url(&self) -> String {
+        match self.addr {
+            Some(addr) => format!("http://{addr}"),
+            None => IN_MEMORY_BASE_URL.to_owned(),
+        }
+    }
+
+    /// Create a transport that passes requests directly to this server without
+    /// going through a socket. The host part of the request URLs is ignored.
+    pub fn transport(&self) -> MockTransport {
+        MockTransport {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Create a client builder with all base URLs pointing to this server. For
+    /// in-memory servers, the builder also uses [`Self::transport`].
+    pub fn client_builder(&self) -> FusClientBuilder {
+        let keys = self.state.lock().unwrap().keys.clone();
+        let url = self.base_url();
+
+        let builder = FusClientBuilder::new(keys)
+            .fota_base_url(&url)
+            .fus_base_url(&url)
+            .download_base_url(&url);
+
+        match self.addr {
+            Some(_) => builder,
+            None => builder.transport(self.transport()),
+        }
+    }
+
+    /// Get the number of requests received so far.
+    pub fn stats(&self) -> MockStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Make the next `count` download requests fail with HTTP 500.
+    pub fn fail_downloads(&self, count: usize) {
+        self.state.lock().unwrap().fail_downloads = count;
+    }
+
+    /// Make the next `count` download requests fail with HTTP 401, as if the
+    /// download sessions expired. Every session is forgotten, so clients need
+    /// to send a new init request before downloading again.
+    pub fn expire_sessions(&self, count: usize) {
+        self.state.lock().unwrap().expire_sessions = count;
+    }
+
+    /// Make the next `count` download responses end prematurely after sending
+    /// `len` bytes of the body.
+    pub fn truncate_downloads(&self, count: usize, len: u64) {
+        self.state.lock().unwrap().truncate_downloads = (count, len);
+    }
+
+    /// Make the next `count` download responses stop sending data after `len`
+    /// bytes of the body, while keeping the connection open, like a dead
+    /// connection that the client has not noticed yet.
+    pub fn stall_downloads(&self, count: usize, len: u64) {
+        self.state.lock().unwrap().stall_downloads = (count, len);
+    }
+
+    /// Make the FOTA server report that no firmware exists for a region, like
+    /// the real server does for `ATT` and `VZW`. FUS still serves the region.
+    pub fn disable_fota(&self, region: &str) {
+        self.state.lock().unwrap().fota_disabled.insert(region.to_owned());
+    }
+
+    /// Make FUS reject inform requests for a region unless they include a
+    /// device identifier in the `DEVICE_IMEI_PUSH` field.
+    pub fn require_device_id(&self, region: &str) {
+        self.state.lock().unwrap().device_id_required.insert(region.to_owned());
+    }
+
+    /// Slow down downloads by sleeping for `delay` before sending each 64 KiB
+    /// chunk of the response body.
+    pub fn delay_downloads(&self, delay: Duration) {
+        self.state.lock().unwrap().download_delay = delay;
+    }
+
+    /// Make download responses omit the NONCE header, which the download
+    /// server is not guaranteed to send.
+    pub fn omit_download_nonce(&self) {
+        self.state.lock().unwrap().download_nonce_omitted = true;
+    }
+}
+
+/// Transport that passes requests directly to a [`MockServer`]. Created by
+/// [`MockServer::transport`].
+#[derive(Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl HttpTransport for MockTransport {
+    fn send_streaming(&self, request: FusRequest)
+            -> BoxFuture<'_, Result<Response<BodyStream>, FusError>> {
+        Box::pin(async move {
+            // Cannot fail because the error type is Infallible
+            let r = handle(self.state.clone(), request.map(Body::from)).await.unwrap();
+
+            Ok(r.map(|body| -> BodyStream {
+                Box::pin(body.map(|r| r.map_err(|e| FusError::TransportError(e.into()))))
+            }))
+        })
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+/// Parse the `key="value"` pairs of a FUS Authorization header.
+fn parse_authorization(value: &str) -> HashMap<String, String> {
+    value.trim_start_matches("FUS ")
+        .split(", ")
+        .filter_map(|p| p.split_once('='))
+        .map(|(k, v)| (k.to_owned(), v.trim_matches('"').to_owned()))
+        .collect()
+}
+
+/// Compute the expected `<LOGIC_CHECK>` value for the given data.
+fn logic_check(nonce: &[u8], data: &[u8]) -> String {
+    if data.is_empty() {
+        return String::new();
+    }
+
+    nonce.iter()
+        .map(|c| data[(*c as usize & 0xf) % data.len()] as char)
+        .collect()
+}
+
+/// Compute the expected `<LOGIC_CHECK>` value for a filename.
+fn logic_check_filename(nonce: &[u8], filename: &str) -> String {
+    let mut data = filename.as_bytes();
+
+    if let Some(n) = data.iter().position(|x| *x == b'.') {
+        data = &data[..n];
+    }
+    if data.len() > 16 {
+        data = &data[data.len() - 16..];
+    }
+
+    logic_check(nonce, data)
+}
+
+fn get_elem_text<'a>(elem: &'a Element, path: &[&str]) -> Option<Cow<'a, str>> {
+    let mut result = Some(elem);
+
+    for p in path {
+        result = result.and_then(|e| e.get_child(*p));
+    }
+
+    result.map(|e| e.get_text().unwrap_or(Cow::Borrowed("")))
+}
+
+fn get_fus_field<'a>(elem: &'a Element, field: &str) -> Option<Cow<'a, str>> {
+    get_elem_text(elem, &["FUSBody", "Put", field, "Data"])
+}
+
+/// Build a FUS XML response with the given status and `<Results>` and `<Put>`
+/// fields.
+fn fus_xml(status: &str, results: &[(&str, String)], put: &[(&str, String)]) -> String {
+    let mut xml = String::new();
+
+    xml.push_str("<FUSMsg><FUSHdr><ProtoVer>1.0</ProtoVer></FUSHdr><FUSBody><Results>");
+    write!(xml, "<Status>{status}</Status>").unwrap();
+    for (k, v) in results {
+        write!(xml, "<{k}><Data>{v}</Data></{k}>").unwrap();
+    }
+    xml.push_str("</Results><Put>");
+    for (k, v) in put {
+        write!(xml, "<{k}><Data>{v}</Data></{k}>").unwrap();
+    }
+    xml.push_str("</Put></FUSBody></FUSMsg>");
+
+    xml
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut r = Response::new(Body::empty());
+    *r.status_mut() = status;
+    r
+}
+
+/// Attach the encrypted nonce to the response, like the real FUS server does.
+fn with_nonce(state: &State, mut r: Response<Body>, nonce: &[u8]) -> Response<Body> {
+    r.headers_mut().insert("NONCE", state.encrypt_nonce(nonce).parse().unwrap());
+    r
+}
+
+async fn handle(state: Arc<Mutex<State>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    debug!("Mock request: {} {}", req.method(), req.uri());
+
+    let path = req.uri().path().to_owned();
+
+    let r = match (req.method(), path.as_str()) {
+        (&Method::POST, "/NF_DownloadGenerateNonce.do") => handle_nonce(&state),
+        (&Method::POST, "/NF_DownloadBinaryInform.do") => handle_inform(&state, req).await,
+        (&Method::POST, "/NF_DownloadBinaryInitForMass.do") => handle_init(&state, req).await,
+        (&Method::GET, "/NF_DownloadBinaryForMass.do") => handle_download(&state, req),
+        (&Method::GET, p) if p.starts_with("/firmware/") && p.ends_with("/version.xml") => {
+            handle_fota(&state, p)
+        }
+        _ => empty_response(StatusCode::NOT_FOUND),
+    };
+
+    debug!("Mock response: {}", r.status());
+
+    Ok(r)
+}
+
+fn handle_nonce(state: &Mutex<State>) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    state.stats.nonce += 1;
+
+    let nonce = state.new_nonce();
+    with_nonce(&state, empty_response(StatusCode::OK), &nonce)
+}
+
+/// Authenticate and parse the body of a FUS XML request.
+async fn read_fus_request(
+    state: &Mutex<State>,
+    req: Request<Body>,
+) -> Result<([u8; 16], Element), Response<Body>> {
+    let nonce = state.lock().unwrap().authenticate(&req)
+        .ok_or_else(|| empty_response(StatusCode::UNAUTHORIZED))?;
+
+    let data = body::to_bytes(req.into_body()).await
+        .map_err(|_| empty_response(StatusCode::BAD_REQUEST))?;
+    let root = Element::parse(data.as_ref())
+        .map_err(|_| empty_response(StatusCode::BAD_REQUEST))?;
+
+    Ok((nonce, root))
+}
+
+async fn handle_inform(state: &Mutex<State>, req: Request<Body>) -> Response<Body> {
+    state.lock().unwrap().stats.inform += 1;
+
+    let (nonce, root) = match read_fus_request(state, req).await {
+        Ok(x) => x,
+        Err(r) => return r,
+    };
+
+    let state = state.lock().unwrap();
+    let field = |name| get_fus_field(&root, name).unwrap_or_default().into_owned();
+
+    let version = field("DEVICE_FW_VERSION");
+    if field("LOGIC_CHECK") != logic_check(&nonce, version.as_bytes()) {
+        return with_nonce(&state, Response::new(fus_xml("400", &[], &[]).into()), &nonce);
+    }
+
+    let model = field("DEVICE_MODEL_NAME");
+    let region = field("DEVICE_LOCAL_CODE");
+    let factory = field("BINARY_NATURE") == "1";
+    if state.device_id_required.contains(&region) && field("DEVICE_IMEI_PUSH").is_empty() {
+        // The real server reports that the firmware does not exist
+        return with_nonce(&state, Response::new(fus_xml("408", &[], &[]).into()), &nonce);
+    }
+
+    let latest = state.versions(&model, &region).pop().unwrap_or_default();
+
+    let xml = match state.find_firmware(&model, &region, &version, factory) {
+        Some(f) => {
+            let i = &f.info;
+            fus_xml(
+                "200",
+                &[("LATEST_FW_VERSION", latest)],
+                &[
+                    ("BINARY_NAME", i.filename.clone()),
+                    ("BINARY_BYTE_SIZE", i.size.to_string()),
+                    ("BINARY_CRC", i.crc.to_string()),
+                    ("BINARY_NATURE", u8::from(i.binary_nature).to_string()),
+                    ("CURRENT_DISPLAY_VERSION", i.version.to_string()),
+                    ("CURRENT_OS_VERSION", i.version_name.clone()),
+                    ("DEVICE_PLATFORM", i.platform.clone()),
+                    ("DEVICE_MODEL_NAME", i.model.clone()),
+                    ("DEVICE_MODEL_DISPLAYNAME", i.model_name.clone()),
+                    ("DEVICE_MODEL_TYPE", i.model_type.to_string()),
+                    ("DEVICE_LOCAL_CODE", i.region.clone()),
+                    ("MODEL_PATH", i.path.clone()),
+                    ("LAST_MODIFIED", i.last_modified.format(LAST_MODIFIED_FORMAT).unwrap()),
+                    ("LOGIC_OPTION_HOME", u8::from(i.logic_option_home).to_string()),
+                    ("LOGIC_OPTION_FACTORY", u8::from(i.logic_option_factory).to_string()),
+                    ("LOGIC_VALUE_HOME", i.logic_value_home.clone()),
+                    ("LOGIC_VALUE_FACTORY", i.logic_value_factory.clone()),
+                ],
+            )
+        }
+        None if !latest.is_empty() => fus_xml("408", &[("LATEST_FW_VERSION", latest)], &[]),
+        None => fus_xml("404", &[], &[]),
+    };
+
+    with_nonce(&state, Response::new(xml.into()), &nonce)
+}
+
+async fn handle_init(state: &Mutex<State>, req: Request<Body>) -> Response<Body> {
+    state.lock().unwrap().stats.init += 1;
+
+    let (nonce, root) = match read_fus_request(state, req).await {
+        Ok(x) => x,
+        Err(r) => return r,
+    };
+
+    let mut state = state.lock().unwrap();
+    let field = |name| get_fus_field(&root, name).unwrap_or_default().into_owned();
+
+    let filename = field("BINARY_FILE_NAME");
+    let status = if field("LOGIC_CHECK") != logic_check_filename(&nonce, &filename) {
+        "400"
+    } else if state.firmware.iter().any(|f| f.info.filename == filename) {
+        state.initialized.insert((nonce, filename));
+        "200"
+    } else {
+        "408"
+    };
+
+    with_nonce(&state, Response::new(fus_xml(status, &[], &[]).into()), &nonce)
+}
+
+fn handle_download(state: &Mutex<State>, req: Request<Body>) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    state.stats.download += 1;
+
+    let nonce = match state.authenticate(&req) {
+        Some(n) => n,
+        None => return empty_response(StatusCode::UNAUTHORIZED),
+    };
+
+    if state.fail_downloads > 0 {
+        state.fail_downloads -= 1;
+        return empty_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    if state.expire_sessions > 0 {
+        state.expire_sessions -= 1;
+        state.initialized.clear();
+        return empty_response(StatusCode::UNAUTHORIZED);
+    }
+
+    // The filename is intentionally not URL-decoded, like the real server
+    let file = req.uri().query()
+        .and_then(|q| q.strip_prefix("file="))
+        .unwrap_or_default();
+    let firmware = state.firmware.iter()
+        .find(|f| format!("{}{}", f.info.path, f.info.filename) == file);
+    let firmware = match firmware {
+        Some(f) if state.initialized.contains(&(nonce, f.info.filename.clone())) => f,
+        Some(_) => return empty_response(StatusCode::UNAUTHORIZED),
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+
+    // The client sends an exclusive end offset, but HTTP ranges are inclusive.
+    // Like the real server, just clamp to the end of the file.
+    let size = firmware.ciphertext.len() as u64;
+    let range = req.headers().get(RANGE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("bytes="))
+        .and_then(|h| h.split_once('-'))
+        .and_then(|(s, e)| Some((s.parse::<u64>().ok()?, e.parse::<u64>().ok()?)));
+    let (start, end) = match range {
+        Some((s, e)) if s <= e && s < size => (s, cmp::min(e, size - 1)),
+        _ => return empty_response(StatusCode::RANGE_NOT_SATISFIABLE),
+    };
+
+    let mut data = Bytes::copy_from_slice(&firmware.ciphertext[start as usize..=end as usize]);
+    if state.truncate_downloads.0 > 0 {
+        state.truncate_downloads.0 -= 1;
+
+        // Pretend that the connection was closed early. The Content-Range
+        // header still reports the full range.
+        let limit = cmp::min(data.len() as u64, state.truncate_downloads.1);
+        debug!("Truncating download to {limit} bytes");
+        data.truncate(limit as usize);
+    }
+
+    let data_len = data.len() as u64;
+    let mut stall = false;
+    if state.stall_downloads.0 > 0 {
+        state.stall_downloads.0 -= 1;
+
+        // Unlike with truncation, Content-Length still reports the full body
+        let limit = cmp::min(data_len, state.stall_downloads.1);
+        debug!("Stalling download after {limit} bytes");
+        data.truncate(limit as usize);
+        stall = true;
+    }
+
+    let delay = state.download_delay;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        while !data.is_empty() {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+
+            let n = cmp::min(data.len(), DOWNLOAD_CHUNK_SIZE);
+            if sender.send_data(data.split_to(n)).await.is_err() {
+                return;
+            }
+        }
+
+        if stall {
+            // Keep the sender alive so that the response never completes
+            std::future::pending::<()>().await;
+        }
+    });
+
+    let mut r = Response::new(body);
+    *r.status_mut() = StatusCode::PARTIAL_CONTENT;
+    r.headers_mut().insert(CONTENT_LENGTH, data_len.into());
+    r.headers_mut().insert(CONTENT_RANGE,
+        format!("bytes {start}-{end}/{size}").parse().unwrap());
+
+    if state.download_nonce_omitted {
+        r
+    } else {
+        with_nonce(&state, r, &nonce)
+    }
+}
+
+fn handle_fota(state: &Mutex<State>, path: &str) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    state.stats.fota += 1;
+
+    let pieces: Vec<&str> = path.split('/').collect();
+    let (region, model) = match pieces.as_slice() {
+        ["", "firmware", region, model, "version.xml"] => (*region, *model),
+        _ => return empty_response(StatusCode::FORBIDDEN),
+    };
+
+    let mut versions = if state.fota_disabled.contains(region) {
+        vec![]
+    } else {
+        state.versions(model, region)
+    };
+    let latest = match versions.pop() {
+        Some(v) => v,
+        // The FOTA server returns 403 when the page is not found
+        None => return empty_response(StatusCode::FORBIDDEN),
+    };
+
+    let mut xml = String::new();
+    write!(xml, "<versioninfo><firmware><model>{model}</model><cc>{region}</cc>").unwrap();
+    write!(xml, "<version><latest o=\"13\">{latest}</latest><upgrade>").unwrap();
+    for (i, v) in versions.iter().rev().enumerate() {
+        write!(xml, "<value rcount=\"{}\" fwsize=\"0\">{v}</value>", i + 1).unwrap();
+    }
+    xml.push_str("</upgrade></version></firmware></versioninfo>");
+
+    Response::new(xml.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use samfuslib::{
+        cassette::{Cassette, RecordingTransport, ReplayTransport},
+        crypto::FusFileAes128,
+        fus::{DeviceId, FirmwareInfo, FusClient, FusError},
+        imei::imei_from_prefix,
+        retry::RetryPolicy,
+        version::FwVersion,
+    };
+
+    use super::*;
+
+    fn test_keys() -> FusKeys {
+        FusKeys::new(
+            b"testing_testing_testing_testing_",
+            b"testing_testing_",
+        ).unwrap()
+    }
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn test_firmware() -> Vec<MockFirmware> {
+        let old = "A1/B1".parse::<FwVersion>().unwrap();
+        let new = "A2/B2".parse::<FwVersion>().unwrap();
+
+        vec![
+            MockFirmware::new("SM-T000", "XAA", old, &test_data(1000)),
+            MockFirmware::new("SM-T000", "XAA", new.clone(), &test_data(300_000)),
+            MockFirmware::new("SM-T000", "XAA", new, &test_data(4000))
+                .factory(true)
+                .logic_value("0123456789abcdef"),
+        ]
+    }
+
+    async fn start_server() -> MockServer {
+        MockServer::start(test_keys(), test_firmware()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_latest_version() {
+        let server = start_server().await;
+        let client = server.client_builder().build().unwrap();
+
+        let version = client.get_latest_version("SM-T000", "XAA").await.unwrap();
+        assert_eq!(version, "A2/B2".parse().unwrap());
+
+        let result = client.get_latest_version("SM-T000", "XAR").await;
+        assert!(matches!(result, Err(FusError::FirmwareNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_latest_version_fus() {
+        let server = start_server().await;
+        server.disable_fota("XAA");
+        let client = server.client_builder().build().unwrap();
+
+        let result = client.get_latest_version("SM-T000", "XAA").await;
+        assert!(matches!(result, Err(FusError::FirmwareNotFound)));
+
+        let version = client.get_latest_version_fus("SM-T000", "XAA", false, None).await.unwrap();
+        assert_eq!(version, "A2/B2".parse().unwrap());
+
+        let result = client.get_latest_version_fus("SM-T000", "XAR", false, None).await;
+        assert!(matches!(result, Err(FusError::FusModelRegionMismatch)));
+    }
+
+    #[tokio::test]
+    async fn test_device_id() {
+        let server = start_server().await;
+        server.require_device_id("XAA");
+        let client = server.client_builder().build().unwrap();
+        let version = "A1/B1".parse().unwrap();
+
+        let result = client.get_latest_version_fus("SM-T000", "XAA", false, None).await;
+        assert!(matches!(result, Err(FusError::FusFirmwareNotFound)));
+        let result = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await;
+        assert!(matches!(result, Err(FusError::FusFirmwareNotFound)));
+
+        let imei = DeviceId::Imei(imei_from_prefix("35145120").unwrap());
+        let latest = client.get_latest_version_fus("SM-T000", "XAA", false, Some(&imei)).await.unwrap();
+        assert_eq!(latest, "A2/B2".parse().unwrap());
+
+        let serial = DeviceId::Serial("R52N0000000".to_owned());
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, Some(&serial)).await.unwrap();
+        assert_eq!(info.version, version);
+    }
+
+    #[tokio::test]
+    async fn test_version_history() {
+        let server = start_server().await;
+        let client = server.client_builder().build().unwrap();
+
+        let history = client.get_version_history("SM-T000", "XAA").await.unwrap();
+        let versions: Vec<_> = history.iter()
+            .map(|v| (v.version.to_string(), v.latest))
+            .collect();
+        assert_eq!(versions, [
+            ("A2/B2/A2/A2".to_owned(), true),
+            ("A1/B1/A1/A1".to_owned(), false),
+        ]);
+        assert_eq!(history[1].attributes.get("rcount").map(|s| s.as_str()), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn test_firmware_info() {
+        let server = start_server().await;
+        let client = server.client_builder().build().unwrap();
+
+        let version = "A1/B1".parse().unwrap();
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await.unwrap();
+        assert_eq!(info.version, version);
+        assert_eq!(info.filename, "SM-T000_A1_HOME.zip.enc2");
+        assert_eq!(info.size, 1008);
+        assert_eq!(info.last_modified, time::macros::datetime!(2020-02-26 16:20:05));
+        assert!(!info.binary_nature);
+
+        let version = "A2/B2".parse().unwrap();
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, true, None).await.unwrap();
+        assert_eq!(info.filename, "SM-T000_A2_FAC.zip.enc4");
+        assert_eq!(info.logic_value_factory, "0123456789abcdef");
+        assert!(info.binary_nature);
+
+        let version = "A3/B3".parse().unwrap();
+        let result = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await;
+        assert!(matches!(result, Err(FusError::FusFirmwareNotFound)));
+
+        let result = client.get_firmware_info("SM-T000", "XAR", &version, false, None).await;
+        assert!(matches!(result, Err(FusError::FusModelRegionMismatch)));
+
+        // The nonce is only requested once and then reused
+        assert_eq!(server.stats().nonce, 1);
+        assert_eq!(server.stats().inform, 4);
+    }
+
+    #[tokio::test]
+    async fn test_bad_keys() {
+        let server = start_server().await;
+        let keys = FusKeys::new(
+            b"testing_testing_testing_testing_",
+            b"_gnitset_gnitset",
+        ).unwrap();
+        let client = FusClientBuilder::new(keys)
+            .fus_base_url(&server.base_url())
+            .build()
+            .unwrap();
+
+        let version = "A1/B1".parse().unwrap();
+        let result = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await;
+        assert!(matches!(result, Err(FusError::FusUnauthorized)));
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let server = start_server().await;
+        let client = server.client_builder().build().unwrap();
+
+        for (version, factory) in [("A2/B2", false), ("A2/B2", true)] {
+            let version = version.parse().unwrap();
+            let info = client.get_firmware_info("SM-T000", "XAA", &version, factory, None).await.unwrap();
+
+            // Download in two pieces to exercise ranges and session reuse
+            let session = client.start_download(&info).await.unwrap();
+            let mut data = vec![];
+            for range in [0..info.size / 2, info.size / 2..info.size] {
+                let mut stream = client.download_range(&session, range.clone()).await.unwrap();
+                let mut piece = vec![];
+                while let Some(chunk) = stream.next().await {
+                    piece.extend_from_slice(&chunk.unwrap());
+                }
+
+                // The end offset is inclusive on the server side
+                assert_eq!(piece.len() as u64,
+                           cmp::min(range.end + 1, info.size) - range.start);
+                piece.truncate((range.end - range.start) as usize);
+                data.extend_from_slice(&piece);
+            }
+
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&data);
+            assert_eq!(hasher.finalize(), info.crc);
+
+            FusFileAes128::new(&info.encryption_key().unwrap())
+                .decrypt_in_place(&mut data)
+                .unwrap();
+            assert_eq!(data, test_data(data.len()));
+        }
+
+        assert_eq!(server.stats().init, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_concurrent() {
+        let server = start_server().await;
+        server.omit_download_nonce();
+        let client = Arc::new(server.client_builder().build().unwrap());
+        let version = "A2/B2".parse().unwrap();
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await.unwrap();
+        let session = Arc::new(client.start_download(&info).await.unwrap());
+        let nonces = server.stats().nonce;
+
+        let tasks: Vec<_> = (0..4u64)
+            .map(|i| {
+                let client = client.clone();
+                let session = session.clone();
+                let range = i * info.size / 4..(i + 1) * info.size / 4;
+                tokio::spawn(async move {
+                    let mut stream = client.download_range(&session, range).await.unwrap();
+                    while let Some(chunk) = stream.next().await {
+                        chunk.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // The download responses do not clear the nonce for the next request
+        client.start_download(&info).await.unwrap();
+        assert_eq!(server.stats().nonce, nonces);
+        assert_eq!(server.stats().download, 4);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        let server = MockServer::in_memory(test_keys(), test_firmware());
+        let client = server.client_builder().build().unwrap();
+
+        let version = "A2/B2".parse().unwrap();
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await.unwrap();
+        assert_eq!(info.size, 300_000);
+
+        let mut stream = client.download(&info, 0..info.size).await.unwrap();
+        let mut data = vec![];
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(data, server.state.lock().unwrap().firmware[1].ciphertext);
+
+        let stats = server.stats();
+        assert_eq!((stats.nonce, stats.inform, stats.init, stats.download), (1, 1, 1, 1));
+    }
+
+    #[test]
+    fn test_blocking() {
+        let server = MockServer::in_memory(test_keys(), test_firmware());
+        let client = server.client_builder().build_blocking().unwrap();
+
+        let version = client.get_latest_version("SM-T000", "XAA").unwrap();
+        assert_eq!(version, "A2/B2".parse().unwrap());
+
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).unwrap();
+        let mut reader = client.download(&info, 0..info.size).unwrap();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, server.state.lock().unwrap().firmware[1].ciphertext);
+
+        server.fail_downloads(1);
+        let session = client.start_download(&info).unwrap();
+        let err = client.download_range(&session, 0..info.size).err().unwrap();
+        assert!(matches!(err, FusError::BadHttpResponse(_, _)));
+    }
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let server = MockServer::in_memory(test_keys(), test_firmware());
+        let recorder = RecordingTransport::new(server.transport());
+        let client = server.client_builder().transport(recorder.clone()).build().unwrap();
+
+        async fn fetch(client: &FusClient) -> Result<(FirmwareInfo, Vec<u8>), FusError> {
+            let version = "A1/B1".parse().unwrap();
+            let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await?;
+
+            let mut stream = client.download(&info, 0..info.size).await?;
+            let mut data = vec![];
+            while let Some(chunk) = stream.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+
+            Ok((info, data))
+        }
+
+        let (info, data) = fetch(&client).await.unwrap();
+
+        let mut json = vec![];
+        recorder.cassette().save(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("<DEVICE_MODEL_NAME>"));
+        assert!(!json.contains("signature="));
+        assert!(!json.contains("MOCKNONCE"));
+        assert!(json.contains("<LOGIC_CHECK><Data>[redacted]</Data></LOGIC_CHECK>"));
+
+        // Replay without the server
+        let builder = server.client_builder();
+        drop(server);
+
+        let cassette = Cassette::load(json.as_bytes()).unwrap();
+        assert_eq!(cassette.interactions.len(), 4);
+        let client = builder
+            .transport(ReplayTransport::new(cassette, &test_keys()))
+            .build()
+            .unwrap();
+
+        let (replayed_info, replayed_data) = fetch(&client).await.unwrap();
+        assert_eq!(replayed_info.crc, info.crc);
+        assert_eq!(replayed_data, data);
+
+        // Each interaction is only replayed once
+        let result = fetch(&client).await;
+        assert!(matches!(result, Err(FusError::TransportError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_download_faults() {
+        let server = start_server().await;
+        let client = server.client_builder().build().unwrap();
+
+        let version = "A2/B2".parse().unwrap();
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await.unwrap();
+
+        let no_retry_client = server.client_builder()
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+        server.fail_downloads(1);
+        let result = no_retry_client.download(&info, 0..info.size).await;
+        assert!(matches!(result, Err(FusError::BadHttpResponse(
+            StatusCode::PARTIAL_CONTENT, StatusCode::INTERNAL_SERVER_ERROR))));
+
+        // Server errors are retried after a delay. Starting a download is not
+        // limited by the per-range retry limit.
+        let retry_client = server.client_builder()
+            .retry_policy(RetryPolicy {
+                max_range_retries: 0,
+                max_total_retries: 2,
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let downloads = server.stats().download;
+        server.fail_downloads(2);
+        let _stream = retry_client.download(&info, 0..info.size).await.unwrap();
+        assert_eq!(server.stats().download, downloads + 3);
+
+        server.fail_downloads(3);
+        let result = retry_client.download(&info, 0..info.size).await;
+        assert!(matches!(result, Err(FusError::BadHttpResponse(
+            StatusCode::PARTIAL_CONTENT, StatusCode::INTERNAL_SERVER_ERROR))));
+        assert_eq!(server.stats().download, downloads + 6);
+
+        server.truncate_downloads(1, 1000);
+        let mut stream = client.download(&info, 0..info.size).await.unwrap();
+        let mut received = 0;
+        while let Some(chunk) = stream.next().await {
+            received += chunk.unwrap().len();
+        }
+        assert_eq!(received, 1000);
+
+        let client = server.client_builder()
+            .read_timeout(Some(Duration::from_millis(100)))
+            .build()
+            .unwrap();
+        server.stall_downloads(1, 1000);
+        let mut stream = client.download(&info, 0..info.size).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().len(), 1000);
+        assert!(matches!(stream.next().await, Some(Err(FusError::ReadTimeout(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let server = start_server().await;
+
+        // The hostnames do not resolve, so this only works via the proxy
+        let client = server.client_builder()
+            .fota_base_url("http://fota.invalid")
+            .fus_base_url("http://fus.invalid")
+            .download_base_url("http://download.invalid")
+            .proxy(&server.base_url())
+            .build()
+            .unwrap();
+
+        let version = client.get_latest_version("SM-T000", "XAA").await.unwrap();
+        let info = client.get_firmware_info("SM-T000", "XAA", &version, false, None).await.unwrap();
+        let mut stream = client.download(&info, 0..info.size).await.unwrap();
+        let mut received = 0;
+        while let Some(chunk) = stream.next().await {
+            received += chunk.unwrap().len();
+        }
+        assert_eq!(received as u64, info.size);
+    }
+}
@@ -0,0 +1,123 @@
+use std::ops::Range;
+
+use crc32fast::Hasher;
+
+/// CRC32 checksum of a byte range of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeChecksum {
+    pub range: Range<u64>,
+    pub crc32: u32,
+}
+
+impl RangeChecksum {
+    /// Compute the checksum of `data`, which is located at offset `start`.
+    pub fn new(start: u64, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+
+        Self {
+            range: start..start + data.len() as u64,
+            crc32: hasher.finalize(),
+        }
+    }
+
+    fn hasher(&self) -> Hasher {
+        Hasher::new_with_initial_len(self.crc32, self.range.end - self.range.start)
+    }
+
+    /// Append the checksum of the range immediately following this one.
+    fn extend(&mut self, next: &RangeChecksum) {
+        debug_assert_eq!(self.range.end, next.range.start);
+
+        let mut hasher = self.hasher();
+        hasher.combine(&next.hasher());
+
+        self.range.end = next.range.end;
+        self.crc32 = hasher.finalize();
+    }
+}
+
+/// Set of checksums for non-overlapping byte ranges of a file. Adjacent ranges
+/// are merged as they are added, so once every byte of the file has been
+/// hashed, a single checksum for the whole file remains.
+#[derive(Clone, Debug, Default)]
+pub struct Checksums(Vec<RangeChecksum>);
+
+impl Checksums {
+    /// Add the checksum of a range that does not overlap any existing range.
+    pub fn insert(&mut self, checksum: RangeChecksum) {
+        if checksum.range.start == checksum.range.end {
+            return;
+        }
+
+        let index = self.0.partition_point(|c| c.range.start < checksum.range.start);
+
+        debug_assert!(index == 0 || self.0[index - 1].range.end <= checksum.range.start);
+        debug_assert!(index == self.0.len() || checksum.range.end <= self.0[index].range.start);
+
+        // Merge with the previous range and/or the next range if adjacent
+        let index = if index > 0 && self.0[index - 1].range.end == checksum.range.start {
+            self.0[index - 1].extend(&checksum);
+            index - 1
+        } else {
+            self.0.insert(index, checksum);
+            index
+        };
+
+        if index + 1 < self.0.len() && self.0[index].range.end == self.0[index + 1].range.start {
+            let next = self.0.remove(index + 1);
+            self.0[index].extend(&next);
+        }
+    }
+
+    /// Get the checksums of the ranges, sorted by offset.
+    pub fn ranges(&self) -> &[RangeChecksum] {
+        &self.0
+    }
+
+    /// Get the checksum of the whole file. Returns `None` if some bytes in
+    /// `0..size` have not been hashed.
+    pub fn crc32(&self, size: u64) -> Option<u32> {
+        match self.0.as_slice() {
+            [] if size == 0 => Some(Hasher::new().finalize()),
+            [c] if c.range == (0..size) => Some(c.crc32),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let expected = crc32fast::hash(&data);
+        let checksum = |r: Range<usize>| RangeChecksum::new(r.start as u64, &data[r]);
+
+        // In order
+        let mut checksums = Checksums::default();
+        checksums.insert(checksum(0..300));
+        checksums.insert(checksum(300..1000));
+        assert_eq!(checksums.crc32(1000), Some(expected));
+
+        // Out of order, with a gap that is filled in last
+        let mut checksums = Checksums::default();
+        checksums.insert(checksum(700..1000));
+        checksums.insert(checksum(0..100));
+        checksums.insert(checksum(100..200));
+        assert_eq!(checksums.ranges().len(), 2);
+        assert_eq!(checksums.crc32(1000), None);
+
+        checksums.insert(checksum(200..700));
+        assert_eq!(checksums.ranges(), &[checksum(0..1000)]);
+        assert_eq!(checksums.crc32(1000), Some(expected));
+
+        // Incomplete at the end
+        assert_eq!(checksums.crc32(1001), None);
+
+        // Empty file
+        assert_eq!(Checksums::default().crc32(0), Some(crc32fast::hash(&[])));
+    }
+}
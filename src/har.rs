@@ -0,0 +1,246 @@
+use http::{StatusCode, Uri};
+use samfuslib::cassette::{Cassette, Interaction, RecordedBody};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// HAR format version
+const HAR_VERSION: &str = "1.2";
+
+/// HTTP version reported for all exchanges. Transports do not expose the
+/// negotiated version, but FUS only supports HTTP/1.1.
+const HTTP_VERSION: &str = "HTTP/1.1";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Har {
+    log: Log,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Log {
+    version: &'static str,
+    creator: Creator,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: Request,
+    response: Response,
+    cache: Cache,
+    timings: Timings,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: String,
+    url: String,
+    http_version: &'static str,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    status_text: String,
+    http_version: &'static str,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: u64,
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Cache {}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+/// Convert recorded headers to HAR name/value pairs.
+fn to_name_values(headers: &[(String, String)]) -> Vec<NameValue> {
+    headers.iter()
+        .map(|(n, v)| NameValue { name: n.clone(), value: v.clone() })
+        .collect()
+}
+
+/// Find the value of the first header with the specified name.
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Split the query string of a URL into name/value pairs. Values are not
+/// percent-decoded.
+fn parse_query_string(url: &str) -> Vec<NameValue> {
+    let query = url.parse::<Uri>().ok()
+        .and_then(|u| u.query().map(|q| q.to_owned()))
+        .unwrap_or_default();
+
+    query.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = p.split_once('=').unwrap_or((p, ""));
+            NameValue { name: name.to_owned(), value: value.to_owned() }
+        })
+        .collect()
+}
+
+/// Get the body text and encoding for HAR content.
+fn body_text(body: &RecordedBody) -> (String, Option<&'static str>) {
+    match body {
+        RecordedBody::Text(s) => (s.clone(), None),
+        RecordedBody::Base64(s) => (s.clone(), Some("base64")),
+    }
+}
+
+/// Get the original size of a body and a comment if it was truncated.
+fn body_size(body: &RecordedBody, original_size: Option<u64>) -> (u64, Option<String>) {
+    match original_size {
+        Some(n) => {
+            let stored = body.to_bytes().map(|b| b.len()).unwrap_or_default();
+            (n, Some(format!("Truncated to {stored} bytes")))
+        }
+        None => (body.to_bytes().map(|b| b.len() as u64).unwrap_or_default(), None),
+    }
+}
+
+fn to_entry(interaction: &Interaction) -> Entry {
+    let req = &interaction.request;
+    let resp = &interaction.response;
+
+    let (req_size, req_comment) = body_size(&req.body, req.original_size);
+    let post_data = if req_size > 0 {
+        Some(PostData {
+            mime_type: find_header(&req.headers, "content-type").unwrap_or_default().to_owned(),
+            text: body_text(&req.body).0,
+            comment: req_comment,
+        })
+    } else {
+        None
+    };
+
+    let (resp_size, resp_comment) = body_size(&resp.body, resp.original_size);
+    let (text, encoding) = body_text(&resp.body);
+
+    let (started, wait, receive) = match &interaction.timings {
+        Some(t) => (t.started, t.wait_ms, t.receive_ms),
+        None => (OffsetDateTime::UNIX_EPOCH, 0.0, 0.0),
+    };
+
+    Entry {
+        // Formatting only fails for years that cannot be represented
+        started_date_time: started.format(&Rfc3339).unwrap_or_default(),
+        time: wait + receive,
+        request: Request {
+            method: req.method.clone(),
+            url: req.url.clone(),
+            http_version: HTTP_VERSION,
+            cookies: vec![],
+            headers: to_name_values(&req.headers),
+            query_string: parse_query_string(&req.url),
+            post_data,
+            headers_size: -1,
+            body_size: req_size,
+        },
+        response: Response {
+            status: resp.status,
+            status_text: StatusCode::from_u16(resp.status).ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or_default()
+                .to_owned(),
+            http_version: HTTP_VERSION,
+            cookies: vec![],
+            headers: to_name_values(&resp.headers),
+            content: Content {
+                size: resp_size,
+                mime_type: find_header(&resp.headers, "content-type")
+                    .unwrap_or_default()
+                    .to_owned(),
+                text,
+                encoding,
+                comment: resp_comment,
+            },
+            redirect_url: find_header(&resp.headers, "location").unwrap_or_default().to_owned(),
+            headers_size: -1,
+            body_size: resp_size,
+        },
+        cache: Cache {},
+        timings: Timings {
+            send: 0.0,
+            wait,
+            receive,
+        },
+    }
+}
+
+impl Har {
+    /// Convert the exchanges in a cassette to a HAR archive.
+    pub fn new(cassette: &Cassette) -> Self {
+        Self {
+            log: Log {
+                version: HAR_VERSION,
+                creator: Creator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries: cassette.interactions.iter().map(to_entry).collect(),
+            },
+        }
+    }
+}
@@ -4,26 +4,63 @@ use std::{
     io::{self, Write},
     mem,
     ops::Range,
-    u64,
 };
 
 use log::debug;
+use samfuslib::range::complement_ranges;
 
-use crate::file::{read_all_at, write_all_at};
+use crate::{
+    checksum::{Checksums, RangeChecksum},
+    file::{read_all_at, write_all_at},
+};
 
-// The state file format is a 516-byte block as described below. It stores two
-// fixed-size arrays containing the list of remaining ranges to be downloaded.
-// Each write of the current state will flip between the two arrays.
+// The state file format is a 1200-byte block as described below. It stores two
+// fixed-size arrays containing the list of remaining ranges to be downloaded
+// and the checksums of the ranges that were already downloaded. Each write of
+// the current state will flip between the two arrays.
 //
 // State block:
 // | Offset | Size | Description                 |
 // |--------|------|-----------------------------|
-// | 0      | 1    | Version field (currently 1) |
+// | 0      | 1    | Version field (currently 2) |
+// | 1      | 1    | Parity                      |
+// | 2      | 599  | Ranges block 1 (parity 0)   |
+// | 601    | 599  | Ranges block 2 (parity 1)   |
+//
+// Ranges block:
+// | Offset | Size | Description                                |
+// |--------|------|--------------------------------------------|
+// | 0      | 1    | Flags (bit 0: written on clean exit)       |
+// | 1      | 1    | Number of range pair slots used (max 16)   |
+// | 2      | 8    | Range 1 beginning (big endian)             |
+// | 10     | 8    | Range 1 end (big endian)                   |
+// | ...    | ...  | ...                                        |
+// | 242    | 8    | Range 16 beginning (big endian)            |
+// | 250    | 8    | Range 16 end (big endian)                  |
+// | 258    | 1    | Number of checksum slots used (max 17)     |
+// | 259    | 8    | Checksum 1 range beginning (big endian)    |
+// | 267    | 8    | Checksum 1 range end (big endian)          |
+// | 275    | 4    | Checksum 1 CRC32 (big endian)              |
+// | ...    | ...  | ...                                        |
+// | 579    | 8    | Checksum 17 range beginning (big endian)   |
+// | 587    | 8    | Checksum 17 range end (big endian)         |
+// | 595    | 4    | Checksum 17 CRC32 (big endian)             |
+//
+// The checksums cover exactly the parts of the file that are not in the list
+// of remaining ranges.
+//
+// Version 1 state blocks are 516 bytes and have no flags or checksums. They
+// can still be read so that downloads started by older versions can be
+// resumed:
+//
+// | Offset | Size | Description                 |
+// |--------|------|-----------------------------|
+// | 0      | 1    | Version field (1)           |
 // | 1      | 1    | Parity                      |
 // | 2      | 257  | Ranges block 1 (parity 0)   |
 // | 259    | 257  | Ranges block 2 (parity 1)   |
 //
-// Ranges block:
+// Version 1 ranges block:
 // | Offset | Size | Description                              |
 // |--------|------|------------------------------------------|
 // | 0      | 1    | Number of range pair slots used (max 16) |
@@ -32,17 +69,34 @@ use crate::file::{read_all_at, write_all_at};
 // | ...    | ...  | ...                                      |
 // | 241    | 8    | Range 16 beginning (big endian)          |
 // | 249    | 8    | Range 16 end (big endian)                |
+//
+// The version 2 ranges block 2 does not overlap the version 1 state block, so
+// the first write after reading a version 1 state always goes there.
 
 /// Maximum number of download ranges that can be stored in the state file.
 pub const MAX_RANGES: usize = 16;
 
-const CURRENT_VERSION: u8 = 1;
+/// Maximum number of checksums that can be stored in the state file. The
+/// downloaded parts of the file are the gaps between the remaining ranges.
+const MAX_CHECKSUMS: usize = MAX_RANGES + 1;
+
+const CURRENT_VERSION: u8 = 2;
+
+const V1_VERSION: u8 = 1;
+
+/// Flag indicating that the state was written when the download stopped
+/// cleanly, rather than periodically while it was running.
+const FLAG_CLEAN: u8 = 1 << 0;
 
 const RANGES_BLOCK_SIZE: u64 =
-    mem::size_of::<u8>() as u64 // Number of elements used
-    + MAX_RANGES as u64         // Max elements
-        * 2                     // (start, end) pair
-        * mem::size_of::<u64>() as u64;
+    mem::size_of::<u8>() as u64     // Flags
+    + mem::size_of::<u8>() as u64   // Number of ranges used
+    + MAX_RANGES as u64             // Max ranges
+        * 2                         // (start, end) pair
+        * mem::size_of::<u64>() as u64
+    + mem::size_of::<u8>() as u64   // Number of checksums used
+    + MAX_CHECKSUMS as u64          // Max checksums
+        * (2 * mem::size_of::<u64>() as u64 + mem::size_of::<u32>() as u64);
 
 const VERSION_OFFSET: u64 = 0;
 const VERSION_SIZE: u64 = mem::size_of::<u8>() as u64;
@@ -58,6 +112,28 @@ const STATE2_SIZE: u64 = RANGES_BLOCK_SIZE;
 
 const STATE_BLOCK_SIZE: u64 = STATE2_OFFSET + STATE2_SIZE;
 
+const V1_RANGES_BLOCK_SIZE: u64 =
+    mem::size_of::<u8>() as u64     // Number of ranges used
+    + MAX_RANGES as u64             // Max ranges
+        * 2                         // (start, end) pair
+        * mem::size_of::<u64>() as u64;
+
+const V1_STATE1_OFFSET: u64 = PARITY_OFFSET + PARITY_SIZE;
+const V1_STATE2_OFFSET: u64 = V1_STATE1_OFFSET + V1_RANGES_BLOCK_SIZE;
+
+/// Download state stored in the state block.
+pub struct State {
+    /// Ranges that remain to be downloaded
+    pub ranges: Vec<Range<u64>>,
+    /// Checksums of the ciphertext in the downloaded parts of the file. This is
+    /// `None` for version 1 state blocks, which did not store checksums.
+    pub checksums: Option<Vec<RangeChecksum>>,
+    /// Whether the state was written when the download stopped cleanly. If
+    /// not, the process or system may have crashed and the downloaded data
+    /// may not match the checksums.
+    pub clean: bool,
+}
+
 pub struct StateFile {
     file: File,
     offset: u64,
@@ -95,6 +171,15 @@ impl StateFile {
                 }
             }
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                // State blocks from older versions are shorter. Keep them so
+                // that the download can be resumed.
+                let mut version = [0xffu8; 1];
+                if read_all_at(&mut self.file, &mut version, self.offset).is_ok()
+                        && version[0] != 0xff {
+                    debug!("Initial state block is truncated or from an older version");
+                    return Ok(());
+                }
+
                 debug!("Writing invalid initial state block");
 
                 write_all_at(&mut self.file, &buf, self.offset)?;
@@ -115,12 +200,15 @@ impl StateFile {
     }
 
     /// Read the ranges block at the specified relative offset.
-    fn read_ranges_block(&mut self, block_offset: u64) -> io::Result<Vec<Range<u64>>> {
+    fn read_ranges_block(&mut self, block_offset: u64) -> io::Result<State> {
         let mut buf = [0u8; RANGES_BLOCK_SIZE as usize];
         let mut pos = 0;
 
         read_all_at(&mut self.file, &mut buf, self.offset + block_offset)?;
 
+        let flags = buf[pos];
+        pos += 1;
+
         let size = buf[pos] as usize;
         pos += 1;
 
@@ -129,21 +217,83 @@ impl StateFile {
                 format!("Too many ranges: {size}")));
         }
 
-        let mut result = Vec::new();
+        let mut ranges = parse_ranges(&buf[pos..], size);
+        pos += MAX_RANGES * 16;
+
+        let num_checksums = buf[pos] as usize;
+        pos += 1;
+
+        if num_checksums > MAX_CHECKSUMS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Too many checksums: {num_checksums}")));
+        }
+
+        let mut checksums = Vec::new();
 
-        for _ in 0..size {
+        for _ in 0..num_checksums {
             let start = u64::from_be_bytes(buf[pos..pos + 8].try_into().unwrap());
             pos += 8;
             let end = u64::from_be_bytes(buf[pos..pos + 8].try_into().unwrap());
             pos += 8;
+            let crc32 = u32::from_be_bytes(buf[pos..pos + 4].try_into().unwrap());
+            pos += 4;
+
+            checksums.push(RangeChecksum { range: start..end, crc32 });
+        }
+
+        debug!("Validating ranges block data: {ranges:?}, {checksums:?}");
+
+        self.validate_ranges(&mut ranges)?;
+
+        let completed = complement_ranges(&ranges, 0..self.offset);
+
+        if !checksums.iter().map(|c| &c.range).eq(completed.iter()) {
+            debug!("Checksums do not cover the downloaded ranges: {completed:?}");
+
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "Checksums do not cover the downloaded ranges"));
+        }
+
+        Ok(State {
+            ranges,
+            checksums: Some(checksums),
+            clean: flags & FLAG_CLEAN != 0,
+        })
+    }
+
+    /// Read the version 1 ranges block at the specified relative offset. The
+    /// state is reported as unclean because version 1 did not record whether
+    /// the download stopped cleanly.
+    fn read_v1_ranges_block(&mut self, block_offset: u64) -> io::Result<State> {
+        let mut buf = [0u8; V1_RANGES_BLOCK_SIZE as usize];
+
+        read_all_at(&mut self.file, &mut buf, self.offset + block_offset)?;
 
-            result.push(start..end);
+        let size = buf[0] as usize;
+
+        if size > MAX_RANGES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Too many ranges: {size}")));
         }
 
-        debug!("Validating ranges block data: {result:?}");
+        let mut ranges = parse_ranges(&buf[1..], size);
+
+        debug!("Validating version 1 ranges block data: {ranges:?}");
+
+        self.validate_ranges(&mut ranges)?;
 
-        result.sort_by_key(|r| r.start);
-        result.retain(|r| r.end - r.start > 0);
+        Ok(State {
+            ranges,
+            checksums: None,
+            clean: false,
+        })
+    }
+
+    /// Sort the ranges, remove empty ones, and make sure that they do not
+    /// overlap.
+    fn validate_ranges(&self, ranges: &mut Vec<Range<u64>>) -> io::Result<()> {
+        ranges.sort_by_key(|r| r.start);
+        ranges.retain(|r| r.end - r.start > 0);
 
         let is_increasing = |w: &[Range<u64>]| {
             w[0].start <= w[0].end
@@ -152,35 +302,57 @@ impl StateFile {
             && w[1].end <= self.offset
         };
 
-        if !result.windows(2).all(is_increasing) {
-            debug!("Ranges overlap or are not increasing: {result:?}");
+        if !ranges.windows(2).all(is_increasing) {
+            debug!("Ranges overlap or are not increasing: {ranges:?}");
 
             return Err(io::Error::new(io::ErrorKind::InvalidData,
                 "Ranges overlap or are not increasing"));
         }
 
-        Ok(result)
+        Ok(())
     }
 
-    /// Write the specified ranges to the ranges block at the specified relative
-    /// offset.
-    fn write_ranges_block(&mut self, ranges: &[Range<u64>], block_offset: u64) -> io::Result<()> {
-        assert!(ranges.len() <= MAX_RANGES);
-
+    /// Write the specified ranges and checksums to the ranges block at the
+    /// specified relative offset.
+    fn write_ranges_block(
+        &mut self,
+        ranges: &[Range<u64>],
+        checksums: &Checksums,
+        clean: bool,
+        block_offset: u64,
+    ) -> io::Result<()> {
         let mut input = ranges.to_owned();
         input.sort_by_key(|r| r.start);
         input.retain(|r| r.end - r.start > 0);
 
+        assert!(input.len() <= MAX_RANGES);
+        assert!(checksums.ranges().len() <= MAX_CHECKSUMS);
+
         let mut buf = [0u8; RANGES_BLOCK_SIZE as usize];
         let mut pos = 0;
+        buf[pos] = if clean { FLAG_CLEAN } else { 0 };
+        pos += 1;
         buf[pos] = input.len() as u8;
         pos += 1;
 
-        for r in input {
-            buf[pos..pos + 8].copy_from_slice(&r.start.to_be_bytes());
+        for i in 0..MAX_RANGES {
+            if let Some(r) = input.get(i) {
+                buf[pos..pos + 8].copy_from_slice(&r.start.to_be_bytes());
+                buf[pos + 8..pos + 16].copy_from_slice(&r.end.to_be_bytes());
+            }
+            pos += 16;
+        }
+
+        buf[pos] = checksums.ranges().len() as u8;
+        pos += 1;
+
+        for c in checksums.ranges() {
+            buf[pos..pos + 8].copy_from_slice(&c.range.start.to_be_bytes());
             pos += 8;
-            buf[pos..pos + 8].copy_from_slice(&r.end.to_be_bytes());
+            buf[pos..pos + 8].copy_from_slice(&c.range.end.to_be_bytes());
             pos += 8;
+            buf[pos..pos + 4].copy_from_slice(&c.crc32.to_be_bytes());
+            pos += 4;
         }
 
         write_all_at(&mut self.file, &buf, self.offset + block_offset)
@@ -188,63 +360,68 @@ impl StateFile {
 
     /// Read the current state from the file. This will read one of the two
     /// states based on the last successfully written parity.
-    pub fn read_state(&mut self) -> io::Result<Vec<Range<u64>>> {
-        let mut version = [0u8; 1];
+    pub fn read_state(&mut self) -> io::Result<State> {
+        let mut header = [0u8; 2];
         read_all_at(
             &mut self.file,
-            &mut version,
+            &mut header,
             self.offset + VERSION_OFFSET,
         )?;
 
-        if version[0] != CURRENT_VERSION {
-            return Err(io::Error::new(io::ErrorKind::InvalidData,
-                format!("Unrecognized state version: {}", version[0])));
-        }
-
-        let mut parity = [0u8; 1];
-        read_all_at(
-            &mut self.file,
-            &mut parity,
-            self.offset + PARITY_OFFSET,
-        )?;
-
-        let new_parity = parity[0] != 0;
+        let version = header[VERSION_OFFSET as usize];
+        let new_parity = header[PARITY_OFFSET as usize] != 0;
 
-        let block_offset = if new_parity { STATE2_OFFSET } else { STATE1_OFFSET };
-        let ranges = self.read_ranges_block(block_offset)?;
+        let state = match version {
+            CURRENT_VERSION => {
+                let block_offset = if new_parity { STATE2_OFFSET } else { STATE1_OFFSET };
+                self.read_ranges_block(block_offset)?
+            }
+            V1_VERSION => {
+                let block_offset = if new_parity { V1_STATE2_OFFSET } else { V1_STATE1_OFFSET };
+                self.read_v1_ranges_block(block_offset)?
+            }
+            v => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Unrecognized state version: {v}")));
+            }
+        };
 
-        debug!("Read ranges for parity {}: {ranges:?}", u8::from(new_parity));
+        debug!("Read version {version} ranges for parity {}: {:?}",
+            u8::from(new_parity), state.ranges);
 
-        self.parity_bit = new_parity;
+        // The next write must go to ranges block 2 if this was a version 1
+        // state because ranges block 1 overlaps the version 1 state block
+        self.parity_bit = new_parity && version == CURRENT_VERSION;
         self.invalid = false;
 
-        Ok(ranges)
+        Ok(state)
     }
 
     /// Write the given state to the file. This will write the new state to the
     /// opposite parity block of the previous state. The previous state block is
     /// never overwritten to reduce the chance of an unclean shutdown corrupting
-    /// the file.
-    pub fn write_state(&mut self, ranges: &[Range<u64>]) -> io::Result<()> {
+    /// the file. `clean` should only be set when the download has stopped and
+    /// the downloaded data has been synced to disk.
+    pub fn write_state(
+        &mut self,
+        ranges: &[Range<u64>],
+        checksums: &Checksums,
+        clean: bool,
+    ) -> io::Result<()> {
         let new_parity = !self.parity_bit;
 
-        debug!("Writing ranges for parity {}: {ranges:?}", u8::from(new_parity));
-
-        write_all_at(
-            &mut self.file,
-            &[CURRENT_VERSION],
-            self.offset + VERSION_OFFSET,
-        )?;
-        self.file.flush()?;
+        debug!("Writing ranges for parity {} (clean: {clean}): {ranges:?}", u8::from(new_parity));
 
         let block_offset = if new_parity { STATE2_OFFSET } else { STATE1_OFFSET };
-        self.write_ranges_block(ranges, block_offset)?;
+        self.write_ranges_block(ranges, checksums, clean, block_offset)?;
         self.file.flush()?;
 
+        // The version and parity are written together so that upgrading a
+        // version 1 state block switches to the new ranges block in one write
         write_all_at(
             &mut self.file,
-            &[u8::from(new_parity)],
-            self.offset + PARITY_OFFSET,
+            &[CURRENT_VERSION, u8::from(new_parity)],
+            self.offset + VERSION_OFFSET,
         )?;
         self.file.flush()?;
 
@@ -254,3 +431,100 @@ impl StateFile {
         Ok(())
     }
 }
+
+/// Parse the first `size` of the [`MAX_RANGES`] (start, end) pairs in `buf`.
+fn parse_ranges(buf: &[u8], size: usize) -> Vec<Range<u64>> {
+    buf.chunks_exact(16)
+        .take(size)
+        .map(|c| {
+            let start = u64::from_be_bytes(c[..8].try_into().unwrap());
+            let end = u64::from_be_bytes(c[8..].try_into().unwrap());
+            start..end
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 1000;
+
+    fn checksums(ranges: &[Range<u64>]) -> Checksums {
+        let mut checksums = Checksums::default();
+        for r in ranges {
+            checksums.insert(RangeChecksum::new(r.start, &vec![0xaa; (r.end - r.start) as usize]));
+        }
+        checksums
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_round_trip() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(SIZE).unwrap();
+
+        let mut state_file = StateFile::new(file.try_clone().unwrap(), SIZE).unwrap();
+        assert!(!state_file.is_valid());
+
+        let remaining = [100..200, 500..1000];
+        let completed = checksums(&[0..100, 200..500]);
+        state_file.write_state(&remaining, &completed, false).unwrap();
+        state_file.write_state(&remaining[1..], &checksums(&[0..500]), true).unwrap();
+
+        let mut state_file = StateFile::new(file.try_clone().unwrap(), SIZE).unwrap();
+        assert!(state_file.is_valid());
+
+        let state = state_file.read_state().unwrap();
+        assert_eq!(state.ranges, &remaining[1..]);
+        assert_eq!(state.checksums.as_deref(), Some(checksums(&[0..500]).ranges()));
+        assert!(state.clean);
+
+        // Checksums must cover exactly the downloaded ranges
+        state_file.write_state(&remaining, &checksums(&[0..100]), false).unwrap();
+        let err = StateFile::new(file, SIZE).unwrap().read_state().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_read_v1() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(SIZE).unwrap();
+
+        // Ranges block 2 is the current one
+        let mut block = vec![0u8; (V1_STATE2_OFFSET + V1_RANGES_BLOCK_SIZE) as usize];
+        block[VERSION_OFFSET as usize] = V1_VERSION;
+        block[PARITY_OFFSET as usize] = 1;
+        let mut pos = V1_STATE2_OFFSET as usize;
+        block[pos] = 2;
+        pos += 1;
+        for r in [500u64..1000, 100..200] {
+            block[pos..pos + 8].copy_from_slice(&r.start.to_be_bytes());
+            block[pos + 8..pos + 16].copy_from_slice(&r.end.to_be_bytes());
+            pos += 16;
+        }
+        write_all_at(&mut file, &block, SIZE).unwrap();
+
+        let mut state_file = StateFile::new(file.try_clone().unwrap(), SIZE).unwrap();
+        assert!(state_file.is_valid());
+
+        let state = state_file.read_state().unwrap();
+        assert_eq!(state.ranges, [100..200, 500..1000]);
+        assert_eq!(state.checksums, None);
+        assert!(!state.clean);
+
+        // The upgraded state is written without touching the version 1 ranges
+        let completed = checksums(&[0..100, 200..500]);
+        state_file.write_state(&state.ranges, &completed, false).unwrap();
+
+        let mut buf = vec![0u8; block.len()];
+        read_all_at(&mut file, &mut buf, SIZE).unwrap();
+        assert_eq!(buf[VERSION_OFFSET as usize], CURRENT_VERSION);
+        assert_eq!(buf[V1_STATE1_OFFSET as usize..], block[V1_STATE1_OFFSET as usize..]);
+
+        let state = StateFile::new(file, SIZE).unwrap().read_state().unwrap();
+        assert_eq!(state.ranges, [100..200, 500..1000]);
+        assert_eq!(state.checksums.as_deref(), Some(completed.ranges()));
+        assert!(!state.clean);
+    }
+}